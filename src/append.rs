@@ -0,0 +1,185 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Appending in place: the media data of the new files is written at the end of the file, so the existing bytes aren't touched
+// until it's complete. Anything between the existing `mdat` and the new media data (the old `moov` when it's after `mdat`, `free`
+// boxes) becomes unused bytes of the extended `mdat`, the chunk offsets skip it. If `moov` is before `mdat`, the new `moov` replaces
+// the old one when it fits (together with any `free` boxes following it), otherwise it's written at the end and the old one is turned
+// into `free`. If `moov` is after `mdat`, the new `moov` is written after the new media data, and the `mdat` size is patched last.
+
+use std::io::{ Read, Write, Seek, SeekFrom, Result, Cursor };
+use std::path::Path;
+use std::time::Instant;
+use crate::{ fourcc, typ_to_str, invalid_data, scan_files, writer, walk_boxes, read_provenance, provenance, streaming::MediaCopy, progress::{ Progress, fraction_cb }, DeferredMedia, MergeOptions, MergeReport, ProgressEvent, Provenance };
+
+/// Appends `files` to `output`, which is usually the result of a previous merge. The existing media data isn't rewritten.
+pub fn append_files<P: AsRef<Path>, F: Fn(f64)>(output: &P, files: &[P], progress_cb: F) -> Result<MergeReport> {
+    let mut f = std::fs::OpenOptions::new().read(true).write(true).open(output)?;
+    let size = f.metadata()?.len() as usize;
+    let mut open_files = Vec::with_capacity(files.len());
+    for x in files {
+        let f = std::fs::File::open(x)?;
+        let size = f.metadata()?.len() as usize;
+        open_files.push((f, size));
+    }
+    let source_names = std::iter::once(output.as_ref()).chain(files.iter().map(|x| x.as_ref()))
+        .map(|x| x.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default()).collect();
+    let options = MergeOptions { source_names, ..Default::default() };
+    append_file_streams(&mut (&mut f, size), &mut open_files, &options, progress_cb)
+}
+
+/// Appends `files` to `output` in place. In the returned report, input 0 is `output` and the appended files start at 1.
+/// The provenance box of `output` is extended if it has one, or written if [`MergeOptions::provenance`] is set.
+/// The progress and [`MergeOptions::cancel`] work like in a merge.
+///
+/// Trimming, filtering tracks, checksums and fast start aren't supported.
+pub fn append_file_streams<F: Fn(f64), O: Read + Write + Seek, I: Read + Seek>(output: &mut (O, usize), files: &mut [(I, usize)], options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    append_streams(output, files, options, &mut fraction_cb(&progress_cb))
+}
+
+/// The `file` of the [`ProgressEvent::Copying`] events is the index in `files`
+pub(crate) fn append_streams<O: Read + Write + Seek, I: Read + Seek>(output: &mut (O, usize), files: &mut [(I, usize)], options: &MergeOptions, progress_cb: &mut dyn FnMut(ProgressEvent)) -> Result<MergeReport> {
+    if !options.keep.is_empty() || options.tracks.is_some() || options.checksum.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Trimming, filtering tracks and checksums aren't supported when appending"));
+    }
+    let output_size = output.1 as u64;
+    let existing_provenance = read_provenance(&mut output.0)?;
+
+    let scan_start = Instant::now();
+    let mut streams = Vec::with_capacity(files.len() + 1);
+    streams.push((Stream::Output(&mut output.0), output.1));
+    streams.extend(files.iter_mut().map(|x| (Stream::Input(&mut x.0), x.1)));
+    let scan = scan_files(&mut streams, options, progress_cb)?;
+    if !scan.usable[0] {
+        return Err(invalid_data("The file to append to can't be merged"));
+    }
+    if scan.insta360_max_read.is_some() {
+        return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "Appending to files with Insta360 metadata isn't supported"));
+    }
+    let (mut desc, mut report, usable) = (scan.desc, scan.report, scan.usable);
+    report.scan_time = scan_start.elapsed();
+    let write_start = Instant::now();
+
+    // Layout of the existing file
+    let mut mdat = None;
+    let mut moov = None;
+    let mut after_moov = Vec::new(); // free boxes directly after moov
+    output.0.seek(SeekFrom::Start(0))?;
+    for x in walk_boxes(&mut output.0)? {
+        let x = x?;
+        if x.depth > 0 { continue; }
+        let h = x.header;
+        let typ = &h.typ.to_be_bytes();
+        match typ {
+            b"mdat" if mdat.is_some() => return Err(invalid_data("Appending to files with multiple mdat boxes isn't supported")),
+            b"mdat" => mdat = Some(h),
+            b"moov" => moov = Some(h),
+            b"free" | b"skip" if moov.is_some_and(|m| after_moov.last().map_or(m.end(), |x: &crate::BoxHeader| x.end()) == h.offset) => after_moov.push(h),
+            _ => { }
+        }
+        if mdat.is_some_and(|m| h.offset >= m.end()) && !matches!(typ, b"moov" | b"free" | b"skip") {
+            return Err(invalid_data(format!("Can't append in place, {} follows mdat", typ_to_str(h.typ))));
+        }
+    }
+    let (mdat, moov) = mdat.zip(moov).ok_or_else(|| invalid_data("No mdat or moov box found"))?;
+    if desc.mdat_position.first() != Some(&(Some(0), mdat.payload_offset(), mdat.payload_size())) {
+        return Err(invalid_data("The mdat box extends past the end of the file"));
+    }
+
+    // The new media data goes after everything in the file
+    let gap = output_size - mdat.end();
+    for t in &mut desc.moov_tracks {
+        for x in t.stco.iter_mut().filter(|x| **x >= mdat.payload_size()) { *x += gap; }
+    }
+    for x in report.inputs.iter_mut().filter(|x| x.index > 0) {
+        x.data_offset += gap;
+    }
+    desc.mdat_offset += gap;
+    let data_end = mdat.payload_offset() + desc.mdat_offset;
+    let new_mdat_size = data_end - mdat.offset;
+    if mdat.header_size == 8 && new_mdat_size > u32::MAX as u64 {
+        return Err(invalid_data("Can't extend a 32-bit mdat past 4 GB"));
+    }
+
+    if options.provenance || existing_provenance.is_some() {
+        let mut sources = provenance::sources(&report.inputs, &desc, |i| if i == 0 { output_size } else { files[i - 1].1 as u64 }, &options.source_names);
+        if let Some(existing) = existing_provenance {
+            sources.splice(..1, existing.sources);
+        }
+        desc.provenance = Some(Provenance { timescale: report.timescale, sources }.to_box());
+    }
+
+    // Build the new moov in memory, the media data stays where it is
+    desc.mdat_final_position = mdat.payload_offset();
+    output.0.seek(SeekFrom::Start(moov.offset))?;
+    let mut new_moov = Cursor::new(Vec::new());
+    writer::rewrite_from_desc(&mut [(&mut output.0, output.1)], &mut new_moov, &mut desc, &mut Progress::new(&mut |_| { }), 0, moov.size)?;
+    let new_moov = new_moov.into_inner();
+
+    // Copy the new media data. Merged file 0 is the output, the others are `files` without the skipped ones
+    let media = DeferredMedia {
+        ranges: desc.mdat_position[1..].iter().map(|(file, offset, size)| (file.and_then(|x| x.checked_sub(1)), *offset, *size)).collect(),
+        inputs: usable[1..].iter().enumerate().filter(|x| *x.1).map(|x| x.0).collect(),
+    };
+    let mut copy = MediaCopy::new(media, options, Progress::new(progress_cb));
+    output.0.seek(SeekFrom::Start(output_size))?;
+    copy.copy(files, &mut output.0, desc.mdat_offset - mdat.payload_size() - gap, options.cancel.as_ref())?;
+    let mut progress = copy.finish(&mut report)?;
+    progress.event(ProgressEvent::WritingTables);
+
+    let moov_space = after_moov.last().map_or(moov.end(), |x| x.end()) - moov.offset;
+    let in_place = moov.offset < mdat.offset && (new_moov.len() as u64 == moov_space || new_moov.len() as u64 + 8 <= moov_space);
+    if !in_place {
+        // Written before the old moov is replaced, so the file stays readable until the end
+        output.0.write_all(&new_moov)?;
+    }
+    let end = output.0.stream_position()?;
+
+    // Extend the mdat. When moov is after it, this is the last write, the old moov is valid until then
+    if mdat.header_size == 16 {
+        writer::patch_bytes(&mut output.0, mdat.offset + 8, &new_mdat_size.to_be_bytes())?;
+    } else {
+        writer::patch_bytes(&mut output.0, mdat.offset, &(new_mdat_size as u32).to_be_bytes())?;
+    }
+
+    if in_place {
+        output.0.seek(SeekFrom::Start(moov.offset))?;
+        output.0.write_all(&new_moov)?;
+        write_free(&mut output.0, moov_space - new_moov.len() as u64)?;
+    } else if moov.offset < mdat.offset {
+        writer::patch_bytes(&mut output.0, moov.offset + 4, &fourcc("free").to_be_bytes())?;
+    }
+    output.0.flush()?;
+
+    report.output_size = end;
+    for x in &mut report.inputs {
+        x.data_offset += desc.mdat_final_position;
+    }
+    report.write_time = write_start.elapsed();
+    progress.event(ProgressEvent::Finished);
+    Ok(report)
+}
+
+fn write_free<W: Write>(w: &mut W, size: u64) -> Result<()> {
+    if size == 0 { return Ok(()); }
+    w.write_all(&(size as u32).to_be_bytes())?;
+    w.write_all(&fourcc("free").to_be_bytes())?;
+    std::io::copy(&mut std::io::repeat(0).take(size - 8), w)?;
+    Ok(())
+}
+
+// The file to append to and the new files, read together by the scan
+enum Stream<'a, O, I> {
+    Output(&'a mut O),
+    Input(&'a mut I),
+}
+impl<O: Read, I: Read> Read for Stream<'_, O, I> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self { Self::Output(x) => x.read(buf), Self::Input(x) => x.read(buf) }
+    }
+}
+impl<O: Seek, I: Seek> Seek for Stream<'_, O, I> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match self { Self::Output(x) => x.seek(pos), Self::Input(x) => x.seek(pos) }
+    }
+}
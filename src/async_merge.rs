@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Write, SeekFrom, Result, ErrorKind };
+use std::path::Path;
+use std::time::Instant;
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt };
+use crate::{ cancel, insta360, invalid_data, merge_streams, desc_reader::MAX_IN_MEMORY, progress::Progress, streaming::{ MediaCopy, SparseStream }, AtomicOutput, DeferredMedia, MergeOptions, MergeReport, ProgressEvent };
+
+/// Async version of [`join_files_with_options`](crate::join_files_with_options), with the progress of [`Merger::progress`](crate::Merger::progress).
+/// The output is written through an [`AtomicOutput`]. Resuming with [`MergeOptions::journal`] isn't supported.
+/// Planning the merge blocks the executor thread, see [`join_file_streams_async`].
+pub async fn join_files_async<P: AsRef<Path>, F: FnMut(ProgressEvent) + Send>(files: &[P], output_file: &P, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    let mut options = options.clone();
+    if options.provenance && options.source_names.is_empty() {
+        options.source_names = files.iter().map(|x| x.as_ref().file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default()).collect();
+    }
+    let mut inputs = Vec::with_capacity(files.len());
+    for path in files {
+        let f = tokio::fs::File::open(path).await?;
+        let size = f.metadata().await?.len() as usize;
+        inputs.push((f, size));
+    }
+    let mut output = AtomicOutput::create(output_file.as_ref(), files)?;
+    let f_out = tokio::fs::File::from_std(output.file().try_clone()?);
+    let report = join_file_streams_async(&mut inputs, f_out, &options, progress_cb).await?;
+    output.finish(report.output_size)?;
+    Ok(report)
+}
+
+/// Async version of [`join_file_streams_with_options`](crate::join_file_streams_with_options). The merge is planned from the boxes
+/// of the inputs read into memory, then the output is written sequentially, so it doesn't have to be seekable. The `moov` and the
+/// trailer are built before the media data is copied, [`ProgressEvent::Copying`] comes after them. Resuming with
+/// [`MergeOptions::journal`] isn't supported.
+///
+/// Only the reads and writes are async. Planning the merge from the loaded boxes runs synchronously inside the future and blocks the
+/// thread polling it until the `moov` is built, which takes long for long recordings. Run it on a thread where that's acceptable.
+pub async fn join_file_streams_async<I, O, F>(files: &mut [(I, usize)], mut output_file: O, options: &MergeOptions, mut progress_cb: F) -> Result<MergeReport>
+    where I: AsyncRead + AsyncSeek + Unpin, O: AsyncWrite + Unpin, F: FnMut(ProgressEvent) + Send
+{
+    if options.journal.is_some() {
+        return Err(std::io::Error::new(ErrorKind::Unsupported, "Resuming isn't supported by the async merge"));
+    }
+    let mut streams = Vec::with_capacity(files.len());
+    for (f, size) in files.iter_mut() {
+        streams.push((load(f, *size as u64).await?, *size));
+    }
+
+    let mut output = SparseStream::default();
+    let mut media = DeferredMedia::default();
+    let mut report = {
+        let mut cb = |event: ProgressEvent| if event != ProgressEvent::Finished { progress_cb(event) };
+        merge_streams(&mut streams, &mut output, options, &mut cb, None, Some(&mut media))?
+    };
+    drop(streams);
+
+    let copy_start = Instant::now();
+    let mut copy = MediaCopy::new(media, options, Progress::with_callback(&mut progress_cb));
+
+    // Boxes written by the sync merge, with gaps for the media data
+    let mut pos = 0;
+    for (offset, data) in std::mem::take(&mut output.regions) {
+        copy_async(&mut copy, files, &mut output_file, offset - pos, options).await?;
+        output_file.write_all(&data).await?;
+        pos = offset + data.len() as u64;
+    }
+    copy_async(&mut copy, files, &mut output_file, report.output_size.saturating_sub(pos), options).await?;
+    output_file.flush().await?;
+
+    let mut progress = copy.finish(&mut report)?;
+    report.write_time += copy_start.elapsed();
+    progress.event(ProgressEvent::Finished);
+
+    Ok(report)
+}
+
+/// Async version of [`MediaCopy::copy`]
+async fn copy_async<I, O, F>(copy: &mut MediaCopy<'_, F>, files: &mut [(I, usize)], output: &mut O, mut size: u64, options: &MergeOptions) -> Result<()>
+    where I: AsyncRead + AsyncSeek + Unpin, O: AsyncWrite + Unpin, F: FnMut(ProgressEvent)
+{
+    while size > 0 {
+        let (input, file, offset, mut len) = copy.next(size)?;
+        size -= len;
+        let f = &mut files[input].0;
+        f.seek(SeekFrom::Start(offset)).await?;
+        while len > 0 {
+            cancel::check(options.cancel.as_ref())?;
+            let n = len.min(copy.buf.len() as u64) as usize;
+            f.read_exact(&mut copy.buf[..n]).await?;
+            output.write_all(&copy.buf[..n]).await?;
+            copy.copied(file, n);
+            len -= n as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the parts of an input which are needed to plan the merge: the top-level boxes without the media data, and the Insta360 trailer
+async fn load<R: AsyncRead + AsyncSeek + Unpin>(reader: &mut R, size: u64) -> Result<SparseStream> {
+    let mut stream = SparseStream { len: size, ..Default::default() };
+    let mut end = size;
+    if size >= insta360::HEADER_SIZE as u64 {
+        let tail = load_region(&mut stream, reader, size - insta360::HEADER_SIZE as u64, insta360::HEADER_SIZE as u64).await?;
+        if &tail[insta360::HEADER_SIZE-32..] == insta360::MAGIC {
+            let extra_size = u32::from_le_bytes(tail[32..36].try_into().unwrap()) as u64;
+            end = size.checked_sub(extra_size).ok_or_else(|| invalid_data("Invalid Insta360 trailer size"))?;
+            load_region(&mut stream, reader, end, extra_size).await?;
+        }
+    }
+
+    let mut offs = 0u64;
+    while offs + 8 <= end {
+        let header = load_region(&mut stream, reader, offs, (end - offs).min(16)).await?;
+        let (box_size, header_size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            1 if header.len() == 16 => (u64::from_be_bytes(header[8..16].try_into().unwrap()), 16),
+            1 => break,
+            0 => (size - offs, 8),
+            x => (x as u64, 8)
+        };
+        if box_size < header_size { break; }
+        if &header[4..8] != b"mdat" {
+            load_region(&mut stream, reader, offs, box_size.min(end - offs)).await?;
+        }
+        offs = offs.saturating_add(box_size);
+    }
+    Ok(stream)
+}
+
+/// Reads `size` bytes at `offset` into `stream`. Boxes larger than [`MAX_IN_MEMORY`] aren't loaded
+async fn load_region<R: AsyncRead + AsyncSeek + Unpin>(stream: &mut SparseStream, reader: &mut R, offset: u64, size: u64) -> Result<Vec<u8>> {
+    if size > MAX_IN_MEMORY {
+        return Err(invalid_data(format!("Box at {offset} is too large to load ({size} bytes)")));
+    }
+    let mut data = vec![0u8; size as usize];
+    reader.seek(SeekFrom::Start(offset)).await?;
+    reader.read_exact(&mut data).await?;
+    stream.pos = offset;
+    stream.write_all(&data)?;
+    Ok(data)
+}
@@ -1,15 +1,55 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
-use std::io::Write;
+use std::io::{ Seek, Write };
 use std::path::*;
-use mp4_merge::{join_files, update_file_times};
+use mp4_merge::{append_files, to_hex, recover_files, update_file_times, probe, walk_boxes, extract_track, read_provenance, split_file, verify_merge, verify_merge_streams, AtomicOutput, ChecksumAlgorithm, ExtractFormat, Merger, MergeOptions, ProgressEvent, SplitAt, TimeRange, VerifyMode, VerifyReport};
 
 fn main() {
     let _time = std::time::Instant::now();
+    let _ = ctrlc::set_handler(|| {
+        mp4_merge::remove_temporary_files();
+        std::process::exit(130);
+    });
+
+    if std::env::args().nth(1).as_deref() == Some("inspect") {
+        for x in std::env::args().skip(2) {
+            println!("{x}:");
+            if let Err(e) = inspect(Path::new(&x)) {
+                eprintln!("  Error: {e}");
+            }
+        }
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("split") {
+        split(std::env::args().skip(2));
+        println!("\rDone in {:.3}s                ", _time.elapsed().as_millis() as f64 / 1000.0);
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("verify") {
+        verify(std::env::args().skip(2));
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("append") {
+        append(std::env::args().skip(2));
+        println!("\rDone in {:.3}s                ", _time.elapsed().as_millis() as f64 / 1000.0);
+        return;
+    }
+    if std::env::args().nth(1).as_deref() == Some("extract") {
+        extract(std::env::args().skip(2));
+        println!("\rDone in {:.3}s                ", _time.elapsed().as_millis() as f64 / 1000.0);
+        return;
+    }
 
     let mut files = Vec::new();
     let mut output_file = None;
+    let mut recover = false;
+    let mut options = MergeOptions::default();
+    let mut reference = None;
+    let mut probe_only = false;
+    let mut report_file = None;
+    let mut resume = false;
+    let mut verify_mode = None;
 
     let mut args = std::env::args().skip(1);
     while let Some(arg) = args.next() {
@@ -19,31 +59,343 @@ fn main() {
             }
             continue;
         }
+        if arg == "--report" {
+            report_file = args.next().map(PathBuf::from);
+            continue;
+        }
+        if arg == "--provenance" {
+            options.provenance = true;
+            continue;
+        }
+        if arg == "--keep" {
+            // START-END in seconds of the merged file, or INPUT:START-END relative to an input file (counting from 0)
+            for x in args.next().unwrap_or_default().split(',') {
+                let (input, range) = match x.split_once(':') {
+                    Some((input, range)) => (input.trim().parse().ok(), range),
+                    None => (None, x)
+                };
+                match range.split_once('-').and_then(|(a, b)| Some((a.trim().parse().ok()?, b.trim().parse().ok()?))) {
+                    Some((start, end)) => options.keep.push(TimeRange { input, start, end }),
+                    None => eprintln!("Invalid range {x:?}")
+                }
+            }
+            continue;
+        }
+        if arg == "--verify" {
+            verify_mode = Some(VerifyMode::Spot(100));
+            continue;
+        }
+        if arg == "--verify-full" {
+            verify_mode = Some(VerifyMode::Full);
+            continue;
+        }
+        if arg == "--checksum" {
+            // Writes OUT.ALGORITHM with the checksums of the media data of each input and of the output
+            let name = args.next().unwrap_or_default();
+            options.checksum = ChecksumAlgorithm::from_name(&name);
+            if options.checksum.is_none() { eprintln!("Checksum {name:?} isn't supported by this build"); }
+            continue;
+        }
+        if arg == "--resume" {
+            resume = true;
+            continue;
+        }
+        if arg == "--edit-list" {
+            options.edit_list = true;
+            continue;
+        }
+        if arg == "--fast-start" {
+            options.fast_start = true;
+            continue;
+        }
+        if arg == "--tracks" {
+            // Indices of the tracks to keep, comma separated
+            options.tracks = Some(args.next().unwrap_or_default().split(',').filter_map(|x| x.trim().parse().ok()).collect());
+            continue;
+        }
+        if arg == "--buffer-size" {
+            // Size of the copy buffers in KiB
+            options.buffer_size = args.next().and_then(|x| x.trim().parse::<usize>().ok()).unwrap_or(0) * 1024;
+            continue;
+        }
+        if arg == "--skip-trailer" {
+            options.skip_vendor_trailer = true;
+            continue;
+        }
+        if arg == "--tolerant" {
+            options.tolerant = true;
+            continue;
+        }
+        if arg == "--probe" {
+            probe_only = true;
+            continue;
+        }
+        if arg == "--recover" {
+            recover = true;
+            continue;
+        }
+        if arg == "--reference" {
+            if let Some(reference_file) = args.next() {
+                reference = Some(Path::new(&reference_file).to_owned());
+                recover = true;
+            }
+            continue;
+        }
         let p = Path::new(&arg);
         if !p.exists() {
             eprintln!("File doesn't exist {:?}", p);
             continue;
         }
-        println!("Merging file {:?}", p);
         files.push(p.to_owned());
         if output_file.is_none() {
             output_file = Some(p.with_file_name(format!("{}_joined.mp4", p.file_name().unwrap().to_str().unwrap())));
         }
     }
     if files.is_empty() { eprintln!("No input files!"); return; }
-    if output_file.is_none() { eprintln!("Output file not specified!"); return; }
 
+    if probe_only {
+        // The output is often piped to e.g. `head`, which closes it early
+        if let Err(e) = print_probe(&files) {
+            if e.kind() != std::io::ErrorKind::BrokenPipe {
+                eprintln!("Error: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if output_file.is_none() { eprintln!("Output file not specified!"); return; }
     let final_output_file = output_file.as_ref().unwrap();
+    // `--out -` writes to stdout, so the messages go to stderr
+    let to_stdout = final_output_file.as_os_str() == "-";
+    macro_rules! status { ($($x:tt)*) => { if to_stdout { eprint!($($x)*); } else { print!($($x)*); std::io::stdout().flush().unwrap(); } } }
+
+    for x in &files {
+        status!("Merging file {:?}\n", x);
+    }
+    status!("Output file {:?}\n", final_output_file);
+    if to_stdout && (resume || recover || verify_mode.is_some()) {
+        eprintln!("Resuming, recovery and verification need an output file");
+        return;
+    }
+    if resume {
+        let mut journal = final_output_file.clone().into_os_string();
+        journal.push(".journal");
+        options.journal = Some(journal.into());
+    }
+
+    let progress_cb = |event: ProgressEvent| {
+        let Some(progress) = event.fraction() else { return; };
+        match event {
+            ProgressEvent::Copying { bytes_per_sec, eta: Some(eta), .. } => status!("\rMerging... {:.2}% ({:.1} MB/s, {}s left)   ", progress * 100.0, bytes_per_sec / 1e6, eta.as_secs()),
+            _ => status!("\rMerging... {:.2}%", progress * 100.0)
+        }
+    };
+    options.source_names = files.iter().map(|x| x.file_name().unwrap().to_string_lossy().into_owned()).collect();
+    if verify_mode.is_some() && (!options.keep.is_empty() || options.tracks.is_some()) {
+        eprintln!("Trimmed outputs can't be verified");
+        verify_mode = None;
+    }
+    let verify_progress_cb = |progress| {
+        print!("\rVerifying... {:.2}%", progress * 100.0);
+        std::io::stdout().flush().unwrap();
+    };
+    let (report, verify_report) = if recover {
+        let mut streams = recover_files(&files, reference.as_ref()).unwrap();
+        let mut output = AtomicOutput::create(final_output_file, &files).unwrap();
+        let merger = streams.iter_mut().fold(Merger::new(), |m, x| m.input_stream(&mut x.0, x.1));
+        let report = merger.output_stream(output.file()).options(options.clone()).progress(progress_cb).run().unwrap();
+        output.finish(report.output_size).unwrap();
+        let verify_report = verify_mode.map(|mode| {
+            let mut merged = streams.into_iter().enumerate().filter(|(i, _)| !report.skipped.iter().any(|x| x.index == *i)).map(|x| x.1).collect::<Vec<_>>();
+            let f = std::fs::File::open(final_output_file).unwrap();
+            let size = f.metadata().unwrap().len() as usize;
+            verify_merge_streams(&mut (std::io::BufReader::new(f), size), &mut merged, mode, verify_progress_cb).unwrap()
+        });
+        (report, verify_report)
+    } else if to_stdout {
+        let report = Merger::new().inputs(&files).output_writer(std::io::stdout().lock()).options(options.clone()).progress(progress_cb).run().unwrap();
+        (report, None)
+    } else {
+        let report = Merger::new().inputs(&files).output(final_output_file).options(options.clone()).progress(progress_cb).run().unwrap();
+        let verify_report = verify_mode.map(|mode| {
+            let merged = files.iter().enumerate().filter(|(i, _)| !report.skipped.iter().any(|x| x.index == *i)).map(|x| x.1.clone()).collect::<Vec<_>>();
+            verify_merge(final_output_file, &merged, mode, verify_progress_cb).unwrap()
+        });
+        (report, verify_report)
+    };
+    for x in &report.skipped {
+        eprintln!("\rSkipped {:?}: {}", files[x.index], x.reason);
+    }
+    if let Some(verify_report) = verify_report {
+        print_verify_report(&verify_report);
+        if !verify_report.is_ok() { std::process::exit(1); }
+    }
+    if let Some(report_file) = report_file {
+        std::fs::write(report_file, report.to_json()).unwrap();
+    }
+    if let Some(algorithm) = options.checksum {
+        let mut sidecar = format!("# {} of the media data (mdat payload) of each input and of the output\n", algorithm.name());
+        for x in &report.inputs {
+            if let Some(checksum) = &x.checksum {
+                sidecar.push_str(&format!("{}  {}\n", to_hex(checksum), options.source_names[x.index]));
+            }
+        }
+        if let Some(checksum) = &report.checksum {
+            sidecar.push_str(&format!("{}  {}\n", to_hex(checksum), final_output_file.file_name().unwrap().to_string_lossy()));
+        }
+        if to_stdout {
+            eprint!("\r{sidecar}");
+        } else {
+            let mut path = final_output_file.clone().into_os_string();
+            path.push(format!(".{}", algorithm.name()));
+            std::fs::write(path, sidecar).unwrap();
+        }
+    }
 
-    println!("Output file {:?}", final_output_file);
+    if !to_stdout {
+        update_file_times(&files[0], final_output_file);
+    }
 
-    join_files(&files, final_output_file, |progress| {
-        print!("\rMerging... {:.2}%", progress * 100.0);
+    status!("\rDone in {:.3}s                \n", _time.elapsed().as_millis() as f64 / 1000.0);
+}
+
+fn print_verify_report(report: &VerifyReport) {
+    if report.is_ok() {
+        println!("\rVerified, {} samples compared with the inputs", report.samples_checked);
+    } else {
+        eprintln!("\rVerification failed:");
+        for x in &report.mismatches {
+            eprintln!("  {x}");
+        }
+    }
+}
+
+// mp4_merge verify OUTPUT INPUTS... [--full]
+fn verify(args: impl Iterator<Item = String>) {
+    let mut mode = VerifyMode::Spot(100);
+    let mut files = Vec::new();
+    for arg in args {
+        match arg.as_str() {
+            "--full" => mode = VerifyMode::Full,
+            _ => files.push(PathBuf::from(arg))
+        }
+    }
+    if files.len() < 2 { eprintln!("Specify the output and the input files!"); return; }
+    let report = verify_merge(&files[0], &files[1..], mode, |progress| {
+        print!("\rVerifying... {:.2}%", progress * 100.0);
         std::io::stdout().flush().unwrap();
     }).unwrap();
+    print_verify_report(&report);
+    if !report.is_ok() { std::process::exit(1); }
+}
 
-    update_file_times(&files[0], final_output_file);
+fn print_probe(files: &[PathBuf]) -> std::io::Result<()> {
+    let info = probe(files)?;
+    let mut out = std::io::stdout().lock();
+    writeln!(out, "Duration: {:.3}s, media data: {} bytes", info.duration_secs(), info.media_data_size)?;
+    for (i, t) in info.tracks.iter().enumerate() {
+        write!(out, "Track {i}: {} {}", t.handler, t.codec)?;
+        if t.width > 0 { write!(out, " {}x{}", t.width, t.height)?; }
+        if t.sample_rate > 0 { write!(out, " {} Hz", t.sample_rate)?; }
+        writeln!(out, ", {:.3}s, {} samples, {} keyframes, {} bytes", t.duration_secs(), t.sample_count, t.keyframe_count, t.media_data_size)?;
+    }
+    out.flush()
+}
 
-    println!("\rDone in {:.3}s                ", _time.elapsed().as_millis() as f64 / 1000.0);
-    std::io::stdout().flush().unwrap();
+fn inspect(path: &Path) -> std::io::Result<()> {
+    let mut f = std::io::BufReader::new(std::fs::File::open(path)?);
+    for x in walk_boxes(&mut f)? {
+        match x {
+            Ok(x) => println!("{:indent$}{} @ {}, size: {}", "", x.header.typ_str(), x.header.offset, x.header.size, indent = x.depth * 2 + 2),
+            Err(e) => println!("  Error: {e}")
+        }
+    }
+    f.seek(std::io::SeekFrom::Start(0))?;
+    if let Some(provenance) = read_provenance(&mut f)? {
+        println!("Merged from:");
+        for x in &provenance.sources {
+            println!("  {} ({} bytes), starts at {:.3}s, duration {:.3}s", x.name, x.size, x.start_time as f64 / provenance.timescale.max(1) as f64, x.duration as f64 / provenance.timescale.max(1) as f64);
+        }
+    }
+    Ok(())
+}
+
+// mp4_merge extract FILES... --track INDEX|HANDLER|CODEC [--mp4] [--out FILE]
+fn extract(mut args: impl Iterator<Item = String>) {
+    let mut files = Vec::new();
+    let mut track = None;
+    let mut format = ExtractFormat::Raw;
+    let mut output_file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--track" => track = args.next(),
+            "--mp4" => format = ExtractFormat::Mp4,
+            "--out" => output_file = args.next().map(PathBuf::from),
+            _ => files.push(PathBuf::from(arg))
+        }
+    }
+    if files.is_empty() { eprintln!("No input files!"); return; }
+    let Some(track) = track else { eprintln!("Track not specified!"); return; };
+
+    let info = probe(&files).unwrap();
+    // Tracks can be selected by index, or by handler or codec, e.g. `meta`, `gpmd`, `rtmd`
+    let Some(index) = track.parse::<usize>().ok().or_else(|| info.tracks.iter().position(|x| x.handler == track || x.codec == track)) else {
+        eprintln!("Track {track} not found!");
+        return;
+    };
+    let output_file = output_file.unwrap_or_else(|| {
+        let ext = if format == ExtractFormat::Mp4 { "mp4" } else { "bin" };
+        files[0].with_file_name(format!("{}_track{index}.{ext}", files[0].file_name().unwrap().to_str().unwrap()))
+    });
+    println!("Extracting track {index} to {:?}", output_file);
+
+    extract_track(&files, index, &output_file, format, |progress| {
+        print!("\rExtracting... {:.2}%", progress * 100.0);
+        std::io::stdout().flush().unwrap();
+    }).unwrap();
+}
+
+// mp4_merge append EXISTING NEW_FILES...
+fn append(args: impl Iterator<Item = String>) {
+    let files = args.map(PathBuf::from).collect::<Vec<_>>();
+    if files.len() < 2 {
+        eprintln!("Specify the file to append to and at least one file to append!");
+        return;
+    }
+    append_files(&files[0], &files[1..], |progress| {
+        print!("\rAppending... {:.2}%", progress * 100.0);
+        std::io::stdout().flush().unwrap();
+    }).unwrap();
+}
+
+// mp4_merge split FILE --at SECONDS,SECONDS,... | --max-size SIZE[K|M|G] | --provenance
+fn split(mut args: impl Iterator<Item = String>) {
+    let mut file = None;
+    let mut at = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--at" => at = args.next().map(|x| SplitAt::Times(x.split(',').filter_map(|x| x.trim().parse().ok()).collect())),
+            "--max-size" => at = args.next().and_then(|x| {
+                let (num, mul) = match x.to_ascii_uppercase().chars().last() {
+                    Some('K') => (&x[..x.len() - 1], 1u64 << 10),
+                    Some('M') => (&x[..x.len() - 1], 1 << 20),
+                    Some('G') => (&x[..x.len() - 1], 1 << 30),
+                    _ => (&x[..], 1)
+                };
+                num.parse::<f64>().ok().map(|x| SplitAt::MaxSize((x * mul as f64) as u64))
+            }),
+            "--provenance" => at = Some(SplitAt::Provenance),
+            _ => file = Some(PathBuf::from(arg))
+        }
+    }
+    let Some(file) = file else { eprintln!("No input file!"); return; };
+    let Some(at) = at else { eprintln!("Specify --at, --max-size or --provenance!"); return; };
+
+    let parts = split_file(&file, &at, |progress| {
+        print!("\rSplitting... {:.2}%", progress * 100.0);
+        std::io::stdout().flush().unwrap();
+    }).unwrap();
+    for x in parts {
+        println!("\rCreated {:?}", x);
+    }
 }
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, SeekFrom, Result };
+use byteorder::{ BigEndian, ReadBytesExt };
+use crate::{ typ_to_str, invalid_data };
+
+// Same limit as when reading the description, real files don't nest deeper
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoxHeader {
+    pub typ: u32,
+    /// Offset of the box (start of the header) in the stream
+    pub offset: u64,
+    /// Size of the whole box including the header
+    pub size: u64,
+    /// 8, or 16 for boxes with a 64-bit size
+    pub header_size: u8,
+}
+
+impl BoxHeader {
+    /// Reads the header at the current position and leaves the reader at the start of the payload.
+    /// A size of 0 means that the box extends to the end of the stream.
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self> {
+        let pos = reader.stream_position()?;
+        let size = reader.read_u32::<BigEndian>()?;
+        let typ = reader.read_u32::<BigEndian>()?;
+        let (size, header_size) = match size {
+            1 => (reader.read_u64::<BigEndian>()?, 16),
+            0 => { // Box extends to the end of the file
+                let end = reader.seek(SeekFrom::End(0))?;
+                reader.seek(SeekFrom::Start(pos + 8))?;
+                (end.saturating_sub(pos), 8)
+            },
+            _ => (size as u64, 8)
+        };
+        if size < header_size as u64 {
+            return Err(invalid_data(format!("Invalid size {size} of {} at offset {pos}", typ_to_str(typ))));
+        }
+        Ok(Self { typ, offset: pos, size, header_size })
+    }
+
+    /// Box type as a string, e.g. `moov`
+    pub fn typ_str(&self) -> String { typ_to_str(self.typ) }
+    pub fn payload_offset(&self) -> u64 { self.offset + self.header_size as u64 }
+    pub fn payload_size(&self) -> u64 { self.size - self.header_size as u64 }
+    pub fn end(&self) -> u64 { self.offset.saturating_add(self.size) }
+}
+
+#[derive(Debug, Clone)]
+pub struct BoxEntry {
+    pub header: BoxHeader,
+    /// Nesting level, 0 for top-level boxes
+    pub depth: usize,
+    /// Types of this box and all its parents, e.g. `moov/trak/mdia/mdhd`
+    pub path: String,
+}
+
+/// Returns the number of payload bytes preceding the child boxes, or `None` if the box has no children we know how to parse
+fn children_offset(typ: u32) -> Option<u64> {
+    match &typ.to_be_bytes() {
+        b"moov" | b"trak" | b"edts" | b"mdia" | b"minf" | b"dinf" | b"stbl" | b"udta" |
+        b"mvex" | b"moof" | b"traf" | b"mfra" | b"tref" | b"sinf" | b"schi" => Some(0),
+        b"meta" => Some(4), // version and flags
+        b"stsd" | b"dref" => Some(8), // version, flags and entry count
+        b"avc1" | b"avc3" | b"hvc1" | b"hev1" | b"mp4v" | b"encv" => Some(78), // visual sample entry
+        b"mp4a" | b"enca" | b"ipcm" | b"fpcm" => Some(28), // audio sample entry
+        _ => None
+    }
+}
+
+/// Depth-first iterator over all boxes in a stream, see [`walk_boxes`]
+pub struct BoxIter<'a, R: Read + Seek> {
+    reader: &'a mut R,
+    parents: Vec<BoxHeader>,
+    end: u64,
+    done: bool,
+}
+
+/// Walks the box tree of `reader` from its current position in file order, descending into known container boxes.
+/// Iteration stops after the first error (e.g. a box which exceeds its parent).
+pub fn walk_boxes<R: Read + Seek>(reader: &mut R) -> Result<BoxIter<'_, R>> {
+    let pos = reader.stream_position()?;
+    let end = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(pos))?;
+    Ok(BoxIter { reader, parents: Vec::new(), end, done: false })
+}
+
+impl<R: Read + Seek> BoxIter<'_, R> {
+    fn read_next(&mut self) -> Result<Option<BoxEntry>> {
+        let mut pos = self.reader.stream_position()?;
+        while let Some(parent) = self.parents.last() {
+            if pos.saturating_add(8) <= parent.end() { break; }
+            pos = parent.end();
+            self.parents.pop();
+        }
+        let parent_end = self.parents.last().map(|x| x.end()).unwrap_or(self.end);
+        if pos.saturating_add(8) > parent_end {
+            return Ok(None);
+        }
+        self.reader.seek(SeekFrom::Start(pos))?;
+        let header = BoxHeader::read(self.reader)?;
+        if header.end() > parent_end {
+            return Err(invalid_data(format!("{} at offset {} exceeds its parent box or the file size", header.typ_str(), header.offset)));
+        }
+        let depth = self.parents.len();
+        let path = self.parents.iter().chain(std::iter::once(&header)).map(BoxHeader::typ_str).collect::<Vec<_>>().join("/");
+
+        match children_offset(header.typ) {
+            Some(skip) if depth < MAX_DEPTH && header.payload_size() >= skip => {
+                self.reader.seek(SeekFrom::Start(header.payload_offset() + skip))?;
+                self.parents.push(header);
+            },
+            _ => { self.reader.seek(SeekFrom::Start(header.end()))?; }
+        }
+        Ok(Some(BoxEntry { header, depth, path }))
+    }
+}
+
+impl<R: Read + Seek> Iterator for BoxIter<'_, R> {
+    type Item = Result<BoxEntry>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done { return None; }
+        let ret = self.read_next().transpose();
+        if !matches!(ret, Some(Ok(_))) { self.done = true; }
+        ret
+    }
+}
+
@@ -0,0 +1,37 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+
+/// Stops a running merge from another thread, see [`MergeOptions::cancel`](crate::MergeOptions::cancel). Clones share the state.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self { Self::default() }
+    pub fn cancel(&self) { self.0.store(true, Ordering::Relaxed); }
+    pub fn is_cancelled(&self) -> bool { self.0.load(Ordering::Relaxed) }
+}
+
+/// Error of a cancelled merge, wrapped in an `std::io::Error`. See [`is_cancelled`]
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { f.write_str("The merge was cancelled") }
+}
+impl std::error::Error for Cancelled { }
+
+/// Whether `error` comes from a cancelled [`CancellationToken`]
+pub fn is_cancelled(error: &std::io::Error) -> bool {
+    error.get_ref().is_some_and(|x| x.is::<Cancelled>())
+}
+
+pub(crate) fn check(token: Option<&CancellationToken>) -> Result<()> {
+    if token.is_some_and(CancellationToken::is_cancelled) {
+        return Err(std::io::Error::other(Cancelled));
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Result };
+
+/// Checksum of the media data, computed while copying it. Each algorithm is available with the cargo feature of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// 64-bit XXH3, feature `xxhash`
+    #[cfg(feature = "xxhash")]
+    Xxh3,
+    /// SHA-256, feature `sha256`
+    #[cfg(feature = "sha256")]
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3 => "xxh3",
+            #[cfg(feature = "sha256")]
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    /// Parses the name returned by [`name`](Self::name). Returns `None` for unknown names and algorithms which weren't enabled.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            #[cfg(feature = "xxhash")]
+            "xxh3" => Some(Self::Xxh3),
+            #[cfg(feature = "sha256")]
+            "sha256" => Some(Self::Sha256),
+            _ => None
+        }
+    }
+}
+
+/// Formats a checksum as lowercase hex
+pub fn to_hex(checksum: &[u8]) -> String {
+    checksum.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+pub(crate) enum Hasher {
+    #[cfg(feature = "xxhash")]
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            #[cfg(feature = "xxhash")]
+            ChecksumAlgorithm::Xxh3 => Self::Xxh3(Box::default()),
+            #[cfg(feature = "sha256")]
+            ChecksumAlgorithm::Sha256 => Self::Sha256(<sha2::Sha256 as sha2::Digest>::new()),
+        }
+    }
+    #[allow(unused_variables)]
+    pub fn update(&mut self, data: &[u8]) {
+        match *self {
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3(ref mut x) => x.update(data),
+            #[cfg(feature = "sha256")]
+            Self::Sha256(ref mut x) => sha2::Digest::update(x, data),
+        }
+    }
+    pub fn finish(self) -> Vec<u8> {
+        match self {
+            #[cfg(feature = "xxhash")]
+            Self::Xxh3(x) => x.digest().to_be_bytes().to_vec(),
+            #[cfg(feature = "sha256")]
+            Self::Sha256(x) => sha2::Digest::finalize(x).to_vec(),
+        }
+    }
+}
+
+/// Checksums of the whole merged media data and of the data of each file
+pub(crate) struct MediaChecksums {
+    algorithm: ChecksumAlgorithm,
+    total: Hasher,
+    files: Vec<Option<Hasher>>,
+}
+
+impl MediaChecksums {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        Self { algorithm, total: Hasher::new(algorithm), files: Vec::new() }
+    }
+
+    /// Copies everything from `reader` to `writer`, or only updates the checksums without a writer
+    pub fn copy<R: Read, W: Write>(&mut self, file_index: usize, reader: &mut R, writer: Option<&mut W>) -> Result<u64> {
+        if self.files.len() <= file_index { self.files.resize_with(file_index + 1, || None); }
+        let file = self.files[file_index].get_or_insert_with(|| Hasher::new(self.algorithm));
+        copy(reader, writer, &mut [&mut self.total, file])
+    }
+
+    /// Updates the checksums with data of `file_index` copied by the caller
+    pub fn update(&mut self, file_index: usize, data: &[u8]) {
+        if self.files.len() <= file_index { self.files.resize_with(file_index + 1, || None); }
+        self.files[file_index].get_or_insert_with(|| Hasher::new(self.algorithm)).update(data);
+        self.total.update(data);
+    }
+
+    /// Returns the checksum of everything and of each file
+    pub fn finish(self) -> (Vec<u8>, Vec<Option<Vec<u8>>>) {
+        (self.total.finish(), self.files.into_iter().map(|x| x.map(Hasher::finish)).collect())
+    }
+}
+
+/// Copies everything from `reader` to `writer` (if any), updating all `hashers`
+fn copy<R: Read, W: Write>(reader: &mut R, mut writer: Option<&mut W>, hashers: &mut [&mut Hasher]) -> Result<u64> {
+    let mut buf = vec![0u8; 64*1024];
+    let mut total = 0;
+    loop {
+        let read = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        for x in hashers.iter_mut() {
+            x.update(&buf[..read]);
+        }
+        if let Some(w) = writer.as_mut() {
+            w.write_all(&buf[..read])?;
+        }
+        total += read as u64;
+    }
+}
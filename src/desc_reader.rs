@@ -2,8 +2,14 @@
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
 use std::io::{ Read, Seek, Result, SeekFrom };
-use byteorder::{ ReadBytesExt, BigEndian };
-use crate::{ fourcc, read_box, typ_to_str };
+use std::sync::Arc;
+use byteorder::{ ReadBytesExt, LittleEndian, BigEndian };
+use crate::{ fourcc, read_box, typ_to_str, invalid_data, insta360, ChecksumAlgorithm, CancellationToken, kernel_copy::FileHandles };
+
+// Deeper nesting than this doesn't exist in real files, so treat it as a malformed (or looping) structure
+const MAX_DEPTH: usize = 16;
+// Larger boxes with children are parsed from the stream instead of being read into memory, a corrupt size can't cause a huge allocation
+pub(crate) const MAX_IN_MEMORY: u64 = 256*1024*1024;
 
 #[derive(Default, Clone, Debug)]
 pub struct TrackDesc {
@@ -16,6 +22,7 @@ pub struct TrackDesc {
     pub stco: Vec<u64>,
     pub stss: Vec<u32>,
     pub sdtp: Vec<u8>,
+    pub ctts: Vec<(u32, i32)>, // sample_count, composition offset
     pub sample_offset: u32,
     pub chunk_offset: u32,
     pub stsz_sample_size: u32,
@@ -23,56 +30,272 @@ pub struct TrackDesc {
     pub stsc: Vec<(u32, u32, u32)>, // first_chunk, samples_per_chunk, sample_description_index
     pub co64_final_position: u64,
     pub skip: bool,
+    pub codec: u32, // fourcc of the first sample entry in stsd
+    pub nal_length_size: u8, // from avcC/hvcC, 0 for other codecs
+    pub handler: u32, // hdlr handler type, e.g. vide, soun, meta
+    pub width: u16, // from the visual sample entry
+    pub height: u16,
+    pub sample_rate: u32, // from the audio sample entry, 0 if not available
+    pub excluded: bool, // left out of the output
+    pub edits: Vec<(u64, i64)>, // segment_duration, media_time. Replaces elst if not empty
 }
 
 #[derive(Default, Clone, Debug)]
 pub struct Desc {
     pub mdat_position: Vec<(Option<usize>, u64, u64)>, // file path, offset, size
     pub mvhd_timescale_per_file: Vec<u32>,
+    pub mvhd_creation_time_per_file: Vec<u64>,
     pub moov_mvhd_timescale: u32,
     pub moov_mvhd_duration: u64,
     pub moov_tracks: Vec<TrackDesc>,
     pub mdat_offset: u64,
     pub mdat_final_position: u64,
+    pub provenance: Option<Vec<u8>>, // box to append to moov
+    pub mdat_resume: u64, // bytes of the merged mdat data already in the output, which aren't copied again
+    pub checksum: Option<ChecksumAlgorithm>, // computed while copying the mdat data
+    pub checksums: Vec<Option<Vec<u8>>>, // per file
+    pub media_checksum: Option<Vec<u8>>, // of the whole merged mdat data
+    pub fast_start: bool, // write moov before mdat
+    pub cancel: Option<CancellationToken>, // checked while copying the mdat data
+    pub files: Option<Arc<FileHandles>>, // for copying the mdat data in the kernel
+    pub copy_buffer_size: usize, // of each buffer of the pipelined copy
 }
 
-pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_read: u64, file_index: usize) -> Result<()> {
-    let mut tl_track = track;
+/// State of the description before reading a file, to undo it if the file turns out to be unusable
+pub struct Checkpoint {
+    desc: Desc,
+    tables: Vec<[usize; 7]>, // lengths of stts, stsz, stco, stss, sdtp, stsc, ctts
+}
+
+impl Desc {
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            desc: Desc {
+                mdat_position: self.mdat_position.clone(),
+                mvhd_timescale_per_file: self.mvhd_timescale_per_file.clone(),
+                mvhd_creation_time_per_file: self.mvhd_creation_time_per_file.clone(),
+                moov_mvhd_timescale: self.moov_mvhd_timescale,
+                moov_mvhd_duration: self.moov_mvhd_duration,
+                moov_tracks: self.moov_tracks.iter().map(TrackDesc::without_tables).collect(),
+                mdat_offset: self.mdat_offset,
+                mdat_final_position: self.mdat_final_position,
+                provenance: self.provenance.clone(),
+                mdat_resume: self.mdat_resume,
+                checksum: self.checksum,
+                checksums: self.checksums.clone(),
+                media_checksum: self.media_checksum.clone(),
+                fast_start: self.fast_start,
+                cancel: self.cancel.clone(),
+                files: self.files.clone(),
+                copy_buffer_size: self.copy_buffer_size,
+            },
+            tables: self.moov_tracks.iter().map(|t| [t.stts.len(), t.stsz.len(), t.stco.len(), t.stss.len(), t.sdtp.len(), t.stsc.len(), t.ctts.len()]).collect()
+        }
+    }
+
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        let Checkpoint { mut desc, tables } = checkpoint;
+        for ((saved, mut t), len) in desc.moov_tracks.iter_mut().zip(std::mem::take(&mut self.moov_tracks)).zip(tables) {
+            t.stts.truncate(len[0]); t.stsz.truncate(len[1]); t.stco.truncate(len[2]);
+            t.stss.truncate(len[3]); t.sdtp.truncate(len[4]); t.stsc.truncate(len[5]); t.ctts.truncate(len[6]);
+            saved.stts = t.stts; saved.stsz = t.stsz; saved.stco = t.stco;
+            saved.stss = t.stss; saved.sdtp = t.sdtp; saved.stsc = t.stsc; saved.ctts = t.ctts;
+        }
+        *self = desc;
+    }
+
+    /// Checks that the tables read from the last file since `checkpoint` are consistent and describe samples inside its `mdat`
+    pub fn validate(&self, checkpoint: &Checkpoint, file_index: usize) -> Result<()> {
+        if self.mvhd_timescale_per_file.get(file_index).copied().unwrap_or(0) == 0 {
+            return Err(invalid_data("No moov box found"));
+        }
+        let mdat_size = self.mdat_position.last().map(|x| x.2).unwrap_or(0);
+        let mdat_range = checkpoint.desc.mdat_offset..=checkpoint.desc.mdat_offset + mdat_size;
+        let mut total_samples = 0;
+        for (i, t) in self.moov_tracks.iter().enumerate() {
+            let empty = TrackDesc::default();
+            let before = checkpoint.desc.moov_tracks.get(i).unwrap_or(&empty);
+            let len = checkpoint.tables.get(i).copied().unwrap_or_default();
+            if t.skip && file_index > 0 { continue; }
+
+            let samples = t.stsz_count - before.stsz_count;
+            total_samples += samples as u64;
+            if t.stsz_sample_size == 0 && t.stsz.len() - len[1] != samples as usize {
+                return Err(invalid_data(format!("Track {i}: stsz has {} entries for {samples} samples", t.stsz.len() - len[1])));
+            }
+            let stts_samples: u64 = t.stts[len[0]..].iter().map(|x| x.0 as u64).sum();
+            if stts_samples != samples as u64 {
+                return Err(invalid_data(format!("Track {i}: stts describes {stts_samples} samples instead of {samples}")));
+            }
+            let chunks = &t.stco[len[2]..];
+            let mut chunk_samples = 0u64;
+            let stsc = &t.stsc[len[5]..];
+            for (j, x) in stsc.iter().enumerate() {
+                let next_first_chunk = stsc.get(j + 1).map(|next| next.0).unwrap_or(before.chunk_offset + chunks.len() as u32 + 1);
+                chunk_samples += next_first_chunk.saturating_sub(x.0) as u64 * x.1 as u64;
+            }
+            if chunk_samples != samples as u64 {
+                return Err(invalid_data(format!("Track {i}: stsc and stco describe {chunk_samples} samples instead of {samples}")));
+            }
+            if let Some(x) = chunks.iter().find(|x| !mdat_range.contains(x)) {
+                return Err(invalid_data(format!("Track {i}: chunk offset {} is outside of mdat", *x as i64 - checkpoint.desc.mdat_offset as i64)));
+            }
+            let ctts_samples: u64 = t.ctts[len[6]..].iter().map(|x| x.0 as u64).sum();
+            if ctts_samples > samples as u64 {
+                return Err(invalid_data(format!("Track {i}: ctts describes {ctts_samples} samples instead of {samples}")));
+            }
+            if let Some(x) = t.stss[len[3]..].iter().find(|x| **x <= before.stsz_count || **x > t.stsz_count) {
+                return Err(invalid_data(format!("Track {i}: sync sample {} doesn't exist", x - before.stsz_count)));
+            }
+        }
+        if total_samples == 0 {
+            return Err(invalid_data("File has no samples"));
+        }
+        Ok(())
+    }
+}
+
+/// Scans a single input file: locates its `mdat`, checks for the Insta360 trailer and reads the `moov` description into `desc`.
+/// Returns the offset where the Insta360 trailer starts, if the file has one.
+pub fn read_file_desc<R: Read + Seek>(fs: &mut R, filesize: usize, desc: &mut Desc, file_index: usize) -> Result<Option<u64>> {
+    let filesize = filesize as u64;
+    let mut insta360_start = None;
+
+    { // Find mdat first
+        fs.seek(SeekFrom::Start(0))?;
+        let mut found_mdat = false;
+        while let Ok((typ, offs, size, header_size)) = read_box(fs) {
+            let org_pos = fs.stream_position()?;
+            if typ == fourcc("mdat") {
+                log::debug!("Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
+                // A truncated file may declare more data than it actually has
+                let size = size.min(filesize.saturating_sub(offs));
+                desc.mdat_position.push((None, org_pos, size.saturating_sub(header_size as u64)));
+                desc.mdat_final_position = org_pos;
+                found_mdat = true;
+                break;
+            }
+            fs.seek(SeekFrom::Start(offs.saturating_add(size)))?;
+        }
+        if !found_mdat {
+            return Err(invalid_data(format!("No mdat box found in file {file_index}")));
+        }
+
+        if filesize >= insta360::HEADER_SIZE as u64 {
+            fs.seek(SeekFrom::End(-40))?;
+            let mut buf = vec![0u8; 40];
+            fs.read_exact(&mut buf)?;
+            // Check if it's Insta360
+            if &buf[8..] == insta360::MAGIC {
+                let extra_size = (&buf[..]).read_u32::<LittleEndian>()? as u64;
+                insta360_start = Some(filesize.checked_sub(extra_size).ok_or_else(|| invalid_data("Invalid Insta360 trailer size"))?);
+            }
+        }
+
+        fs.seek(SeekFrom::Start(0))?;
+    }
+
+    read_desc(fs, desc, insta360_start.unwrap_or(u64::MAX), file_index)?;
+
+    Ok(insta360_start)
+}
+
+pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, max_read: u64, file_index: usize) -> Result<()> {
+    if desc.mvhd_timescale_per_file.len() <= file_index {
+        desc.mvhd_timescale_per_file.resize(file_index + 1, 0);
+        desc.mvhd_creation_time_per_file.resize(file_index + 1, 0);
+    }
     let start_offs = d.stream_position()?;
-    desc.mvhd_timescale_per_file.push(0);
-    while let Ok((typ, offs, size, header_size)) = read_box(d) {
-        if size == 0 || typ == 0 { continue; }
+    let file_end = d.seek(SeekFrom::End(0))?;
+    d.seek(SeekFrom::Start(start_offs))?;
+    let end = start_offs.saturating_add(max_read).min(file_end);
+
+    // Only the top-level headers are read from the stream. Boxes with children are read at once and parsed from memory, unless they are too large
+    while d.stream_position()? + 8 <= end {
+        let (typ, offs, size, _) = match read_box(d) {
+            Ok(x) => x,
+            Err(e) => { log::warn!("Stopping at invalid top-level box: {e}"); break; }
+        };
+        let box_end = offs.saturating_add(size);
+        if box_end > end {
+            log::warn!("{} at offset {offs} exceeds the file size", typ_to_str(typ));
+            break;
+        }
+        if crate::has_children(typ, true) && size <= MAX_IN_MEMORY {
+            let mut data = vec![0u8; size as usize];
+            d.seek(SeekFrom::Start(offs))?;
+            d.read_exact(&mut data)?;
+            read_boxes(&mut std::io::Cursor::new(data), desc, 0, size, file_index, 0)?;
+        } else if crate::has_children(typ, true) {
+            d.seek(SeekFrom::Start(offs))?;
+            read_boxes(d, desc, 0, box_end, file_index, 0)?;
+        }
+        d.seek(SeekFrom::Start(box_end))?;
+    }
+    Ok(())
+}
+
+fn read_boxes<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, end: u64, file_index: usize, depth: usize) -> Result<()> {
+    if depth > MAX_DEPTH {
+        return Err(invalid_data("Boxes are nested too deep"));
+    }
+    let mut tl_track = track;
+    while d.stream_position()? + 8 <= end {
+        let (typ, offs, size, header_size) = match read_box(d) {
+            Ok(x) => x,
+            Err(e) if depth == 0 => { log::warn!("Stopping at invalid top-level box: {e}"); break; }
+            Err(e) => return Err(e)
+        };
+        let box_end = offs.saturating_add(size);
+        if box_end > end {
+            // Top-level boxes may be truncated or followed by garbage, but nested boxes must fit in their parent
+            if depth == 0 { log::warn!("{} at offset {offs} exceeds the file size", typ_to_str(typ)); break; }
+            return Err(invalid_data(format!("{} at offset {offs} exceeds its parent box", typ_to_str(typ))));
+        }
+        let payload = size - header_size as u64;
+
         if crate::has_children(typ, true) {
-            read_desc(d, desc, tl_track, size - header_size as u64, file_index)?;
+            if typ == fourcc("trak") && desc.moov_tracks.len() <= tl_track {
+                desc.moov_tracks.resize(tl_track + 1, Default::default());
+            }
+            if typ == fourcc("stsd") {
+                read_stsd(d, desc, tl_track, payload)?;
+            } else {
+                read_boxes(d, desc, tl_track, box_end, file_index, depth + 1)?;
+            }
 
             if typ == fourcc("trak") {
                 tl_track += 1;
             }
         } else {
             log::debug!("Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
-            let org_pos = d.stream_position()?;
-            // if typ == fourcc("mdat") {
-            //     desc.mdat_position.push((None, org_pos, size - header_size as u64));
-            //     desc.mdat_final_position = org_pos;
-            // }
             if typ == fourcc("mvhd") || typ == fourcc("tkhd") || typ == fourcc("mdhd") {
                 let (v, _flags) = (d.read_u8()?, d.read_u24::<BigEndian>()?);
+                let needed = match (typ == fourcc("tkhd"), v == 1) {
+                    (false, false) => 4 + 4+4+4+4,
+                    (false, true)  => 4 + 8+8+4+8,
+                    (true,  false) => 4 + 4+4+4+4+4,
+                    (true,  true)  => 4 + 8+8+4+4+8,
+                };
+                check_payload(typ, payload, needed)?;
                 if typ == fourcc("mvhd") {
-                    let timescale = if v == 1 { d.seek(SeekFrom::Current(8+8))?; d.read_u32::<BigEndian>()? }
-                                    else      { d.seek(SeekFrom::Current(4+4))?; d.read_u32::<BigEndian>()? };
+                    let creation_time = if v == 1 { d.read_u64::<BigEndian>()? } else { d.read_u32::<BigEndian>()? as u64 };
+                    let timescale = if v == 1 { d.seek(SeekFrom::Current(8))?; d.read_u32::<BigEndian>()? }
+                                    else      { d.seek(SeekFrom::Current(4))?; d.read_u32::<BigEndian>()? };
                     let duration = if v == 1 { d.read_u64::<BigEndian>()? }
                                    else      { d.read_u32::<BigEndian>()? as u64 };
+                    desc.mvhd_creation_time_per_file[file_index] = creation_time;
                     if desc.moov_mvhd_timescale == 0 {
                         desc.moov_mvhd_timescale = timescale;
                     }
                     desc.mvhd_timescale_per_file[file_index] = timescale;
-                    desc.moov_mvhd_duration += ((duration as f64 / timescale as f64) * desc.moov_mvhd_timescale as f64).ceil() as u64;
+                    desc.moov_mvhd_duration = desc.moov_mvhd_duration.saturating_add(rescale(duration, timescale, desc.moov_mvhd_timescale));
                 }
                 if let Some(track_desc) = desc.moov_tracks.get_mut(tl_track) {
                     if typ == fourcc("tkhd") {
                         let duration = if v == 1 { d.seek(SeekFrom::Current(8+8+4+4))?; d.read_u64::<BigEndian>()? }
                                        else      { d.seek(SeekFrom::Current(4+4+4+4))?; d.read_u32::<BigEndian>()? as u64 };
-                        track_desc.tkhd_duration += ((duration as f64 / *desc.mvhd_timescale_per_file.get(file_index).ok_or(std::io::Error::other("Invalid index"))? as f64) * desc.moov_mvhd_timescale as f64).ceil() as u64;
+                        track_desc.tkhd_duration = track_desc.tkhd_duration.saturating_add(rescale(duration, *desc.mvhd_timescale_per_file.get(file_index).ok_or(std::io::Error::other("Invalid index"))?, desc.moov_mvhd_timescale));
                     }
                     if typ == fourcc("mdhd") {
                         let timescale = if v == 1 { d.seek(SeekFrom::Current(8+8))?; d.read_u32::<BigEndian>()? }
@@ -82,51 +305,77 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                         if track_desc.mdhd_timescale == 0 {
                             track_desc.mdhd_timescale = timescale;
                         }
-                        let add_duration = ((duration as f64 / timescale as f64) * track_desc.mdhd_timescale as f64).ceil() as u64;
-                        track_desc.mdhd_duration += add_duration;
+                        track_desc.mdhd_duration = track_desc.mdhd_duration.saturating_add(rescale(duration, timescale, track_desc.mdhd_timescale));
                     }
                 }
             }
+            if typ == fourcc("hdlr") {
+                if let Some(track_desc) = desc.moov_tracks.get_mut(tl_track).filter(|x| x.handler == 0) {
+                    check_payload(typ, payload, 12)?;
+                    d.seek(SeekFrom::Current(8))?; // Skip version, flags and pre_defined
+                    track_desc.handler = d.read_u32::<BigEndian>()?;
+                }
+            }
             if typ == fourcc("elst") || typ == fourcc("stts") || typ == fourcc("stsz") || typ == fourcc("stss") ||
-               typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") {
-                let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
+               typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") || typ == fourcc("ctts") {
+                let track_desc = desc.moov_tracks.get_mut(tl_track).ok_or_else(|| invalid_data(format!("{} outside of a track", typ_to_str(typ))))?;
                 if !(track_desc.skip && file_index > 0) {
+                    check_payload(typ, payload, 4)?;
                     let (v, _flags) = (d.read_u8()?, d.read_u24::<BigEndian>()?);
+                    let payload = payload - 4;
 
                     if typ == fourcc("elst") {
+                        check_payload(typ, payload, 4)?;
                         let entry_count = d.read_u32::<BigEndian>()?;
+                        check_payload(typ, payload - 4, entry_count as u64 * if v == 1 { 8+8+4 } else { 4+4+4 })?;
                         for _ in 0..entry_count {
                             let segment_duration = if v == 1 { d.read_u64::<BigEndian>()? } else { d.read_u32::<BigEndian>()? as u64 };
                             let media_time       = if v == 1 { d.read_i64::<BigEndian>()? } else { d.read_i32::<BigEndian>()? as i64 };
                             d.seek(SeekFrom::Current(4))?; // Skip Media rate
                             if media_time != -1 {
-                                track_desc.elst_segment_duration += segment_duration;
+                                track_desc.elst_segment_duration = track_desc.elst_segment_duration.saturating_add(segment_duration);
                             }
                         }
                     }
                     if typ == fourcc("stsz") {
+                        check_payload(typ, payload, 8)?;
                         track_desc.stsz_sample_size = d.read_u32::<BigEndian>()?;
                         let count = d.read_u32::<BigEndian>()?;
                         if track_desc.stsz_sample_size == 0 {
+                            check_payload(typ, payload - 8, count as u64 * 4)?;
+                            track_desc.stsz.reserve(count as usize);
                             for _ in 0..count { track_desc.stsz.push(d.read_u32::<BigEndian>()?); }
                         }
-                        track_desc.stsz_count += count;
+                        track_desc.stsz_count = track_desc.stsz_count.checked_add(count).ok_or_else(|| invalid_data("Too many samples"))?;
                     }
                     if typ == fourcc("sdtp") {
-                        let count = size - header_size as u64 - 4;
-                        for _ in 0..count { track_desc.sdtp.push(d.read_u8()?); }
+                        let mut buf = vec![0u8; payload as usize];
+                        d.read_exact(&mut buf)?;
+                        track_desc.sdtp.extend_from_slice(&buf);
+                    }
+                    if typ == fourcc("ctts") && track_desc.ctts.is_empty() && track_desc.sample_offset > 0 {
+                        // Previous files had no composition offsets
+                        track_desc.ctts.push((track_desc.sample_offset, 0));
                     }
-                    if typ == fourcc("stss") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("stts") || typ == fourcc("stsc") {
+                    if typ == fourcc("stss") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("stts") || typ == fourcc("stsc") || typ == fourcc("ctts") {
+                        check_payload(typ, payload, 4)?;
                         let count = d.read_u32::<BigEndian>()?;
-                        let current_file_mdat_position = desc.mdat_position.last().unwrap().1;
+                        let entry_size = if typ == fourcc("stsc") { 12 } else if typ == fourcc("stts") || typ == fourcc("co64") || typ == fourcc("ctts") { 8 } else { 4 };
+                        check_payload(typ, payload - 4, count as u64 * entry_size)?;
+                        let current_file_mdat_position = desc.mdat_position.last().ok_or_else(|| invalid_data("No mdat box found"))?.1;
                         let mdat_offset = desc.mdat_offset as i64 - current_file_mdat_position as i64;
+                        let relative_offset = |offset: u64| -> Result<u64> {
+                            (offset as i64).checked_add(mdat_offset).and_then(|x| u64::try_from(x).ok()).ok_or_else(|| invalid_data("Chunk offset outside of mdat"))
+                        };
                         for _ in 0..count {
-                            if typ == fourcc("stss") { track_desc.stss.push(d.read_u32::<BigEndian>()? + track_desc.sample_offset); }
-                            if typ == fourcc("stco") { track_desc.stco.push((d.read_u32::<BigEndian>()? as i64 + mdat_offset) as u64); }
-                            if typ == fourcc("co64") { track_desc.stco.push((d.read_u64::<BigEndian>()? as i64 + mdat_offset) as u64); }
+                            if typ == fourcc("stss") { track_desc.stss.push(d.read_u32::<BigEndian>()?.checked_add(track_desc.sample_offset).ok_or_else(|| invalid_data("Invalid sync sample"))?); }
+                            if typ == fourcc("stco") { track_desc.stco.push(relative_offset(d.read_u32::<BigEndian>()? as u64)?); }
+                            if typ == fourcc("co64") { track_desc.stco.push(relative_offset(d.read_u64::<BigEndian>()?)?); }
                             if typ == fourcc("stts") { track_desc.stts.push((d.read_u32::<BigEndian>()?, d.read_u32::<BigEndian>()?)); }
+                            // Version 0 offsets are unsigned, but values above i32::MAX don't occur in practice
+                            if typ == fourcc("ctts") { track_desc.ctts.push((d.read_u32::<BigEndian>()?, d.read_i32::<BigEndian>()?)); }
                             if typ == fourcc("stsc") { track_desc.stsc.push((
-                                d.read_u32::<BigEndian>()? + track_desc.chunk_offset,
+                                d.read_u32::<BigEndian>()?.checked_add(track_desc.chunk_offset).ok_or_else(|| invalid_data("Invalid chunk index"))?,
                                 d.read_u32::<BigEndian>()?,
                                 d.read_u32::<BigEndian>()?
                             )); }
@@ -134,16 +383,121 @@ pub fn read_desc<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, max_r
                     }
                 }
             }
-            if typ == fourcc("tmcd") {
-                // Timecode shouldn't be merged
-                let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
-                track_desc.skip = true;
+        }
+        d.seek(SeekFrom::Start(box_end))?;
+    }
+    Ok(())
+}
+
+fn read_stsd<R: Read + Seek>(d: &mut R, desc: &mut Desc, track: usize, payload: u64) -> Result<()> {
+    check_payload(fourcc("stsd"), payload, 8)?;
+    let start = d.stream_position()?;
+    let end = start + payload;
+    d.seek(SeekFrom::Current(8))?; // Skip version, flags and entry count
+    while d.stream_position()? + 8 <= end {
+        let (typ, offs, size, header_size) = read_box(d)?;
+        if offs.saturating_add(size) > end {
+            return Err(invalid_data(format!("Sample entry {} at offset {offs} exceeds stsd", typ_to_str(typ))));
+        }
+        let track_desc = desc.moov_tracks.get_mut(track).ok_or_else(|| invalid_data("stsd outside of a track"))?;
+        if typ == fourcc("tmcd") {
+            // Timecode shouldn't be merged
+            track_desc.skip = true;
+        }
+        if track_desc.codec == 0 {
+            track_desc.codec = typ;
+            let entry_start = offs + header_size as u64;
+            if size - header_size as u64 >= 28 {
+                let mut entry = [0u8; 28];
+                d.read_exact(&mut entry)?;
+                let field = |pos: usize| u16::from_be_bytes([entry[pos], entry[pos + 1]]);
+                if track_desc.handler == fourcc("vide") {
+                    track_desc.width = field(24);
+                    track_desc.height = field(26);
+                }
+                // Version 2 of the QuickTime sound description stores the sample rate as a float in a different place
+                if track_desc.handler == fourcc("soun") && field(8) < 2 {
+                    track_desc.sample_rate = field(24) as u32;
+                }
+            }
+            if typ == fourcc("avc1") || typ == fourcc("avc3") || typ == fourcc("hvc1") || typ == fourcc("hev1") {
+                // Visual sample entry is 78 bytes, followed by the codec configuration box
+                d.seek(SeekFrom::Start(entry_start + 78))?;
+                while d.stream_position()? + 8 <= offs + size {
+                    let (typ, offs, size, _header_size) = read_box(d)?;
+                    if typ == fourcc("avcC") || typ == fourcc("hvcC") {
+                        let skip = if typ == fourcc("avcC") { 4 } else { 21 };
+                        check_payload(typ, size.saturating_sub(8), skip + 1)?;
+                        d.seek(SeekFrom::Current(skip as i64))?;
+                        track_desc.nal_length_size = (d.read_u8()? & 3) + 1;
+                        break;
+                    }
+                    d.seek(SeekFrom::Start(offs.saturating_add(size)))?;
+                }
             }
-            d.seek(SeekFrom::Start(org_pos + size - header_size as u64))?;
         }
-        if d.stream_position()? - start_offs >= max_read {
-            break;
+        d.seek(SeekFrom::Start(offs + size))?;
+    }
+    Ok(())
+}
+
+impl TrackDesc {
+    pub fn without_tables(&self) -> TrackDesc {
+        TrackDesc {
+            tkhd_duration: self.tkhd_duration,
+            elst_segment_duration: self.elst_segment_duration,
+            mdhd_timescale: self.mdhd_timescale,
+            mdhd_duration: self.mdhd_duration,
+            sample_offset: self.sample_offset,
+            chunk_offset: self.chunk_offset,
+            stsz_sample_size: self.stsz_sample_size,
+            stsz_count: self.stsz_count,
+            co64_final_position: self.co64_final_position,
+            skip: self.skip,
+            codec: self.codec,
+            nal_length_size: self.nal_length_size,
+            handler: self.handler,
+            width: self.width,
+            height: self.height,
+            sample_rate: self.sample_rate,
+            excluded: self.excluded,
+            ..Default::default()
+        }
+    }
+    /// Expands `stsc` to the number of samples in each chunk listed in `stco`
+    pub fn samples_per_chunk(&self) -> Vec<u32> {
+        let mut ret = Vec::with_capacity(self.stco.len());
+        for (i, x) in self.stsc.iter().enumerate() {
+            let next_first_chunk = self.stsc.get(i + 1).map(|next| next.0 as usize).unwrap_or(self.stco.len() + 1);
+            for _ in (x.0 as usize).max(1)..next_first_chunk.min(self.stco.len() + 1) {
+                ret.push(x.1);
+            }
+            if ret.len() >= self.stco.len() { break; }
         }
+        ret.resize(self.stco.len(), self.stsc.last().map(|x| x.1).unwrap_or(0));
+        ret
+    }
+    /// Extends `ctts` with zero offsets to cover all samples, if the track has composition offsets at all
+    pub fn pad_ctts(&mut self) {
+        let count: u64 = self.ctts.iter().map(|x| x.0 as u64).sum();
+        if !self.ctts.is_empty() && count < self.stsz_count as u64 {
+            self.ctts.push((self.stsz_count - count as u32, 0));
+        }
+    }
+    /// Size of the sample at `index`
+    pub fn sample_size(&self, index: usize) -> u32 {
+        if self.stsz_sample_size != 0 { self.stsz_sample_size } else { self.stsz.get(index).copied().unwrap_or(0) }
+    }
+}
+
+/// Converts `value` from the `from` timescale to the `to` timescale, rounding up
+pub fn rescale(value: u64, from: u32, to: u32) -> u64 {
+    ((value as f64 / from as f64) * to as f64).ceil() as u64
+}
+
+fn check_payload(typ: u32, payload: u64, needed: u64) -> Result<()> {
+    if payload < needed {
+        return Err(invalid_data(format!("{} is too small ({payload} bytes, expected at least {needed})", typ_to_str(typ))));
     }
     Ok(())
 }
@@ -0,0 +1,84 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+use std::path::Path;
+use crate::{ scan_files, writer, MergeOptions, SampleTable, AtomicOutput, progress::{ Progress, ProgressEvent, MediaRead, fraction_cb } };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExtractFormat {
+    /// Concatenated sample data without any container
+    #[default]
+    Raw,
+    /// MP4 file containing only the extracted track
+    Mp4,
+}
+
+/// Writes the samples of `track` from all `files` to `output_file`, without merging the other tracks
+pub fn extract_track<P: AsRef<Path>, F: Fn(f64)>(files: &[P], track: usize, output_file: &P, format: ExtractFormat, progress_cb: F) -> Result<()> {
+    let mut open_files = Vec::with_capacity(files.len());
+    for x in files {
+        let f = std::fs::File::open(x)?;
+        let size = f.metadata()?.len() as usize;
+        open_files.push((f, size));
+    }
+    let mut output = AtomicOutput::create(output_file.as_ref(), files)?;
+    extract_track_streams(&mut open_files, track, output.file(), format, progress_cb)?;
+    let size = output.file().metadata()?.len();
+    output.finish(size)
+}
+
+pub fn extract_track_streams<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], track: usize, output_file: O, format: ExtractFormat, progress_cb: F) -> Result<()> {
+    extract_streams(files, track, output_file, format, &mut fraction_cb(&progress_cb))
+}
+
+pub(crate) fn extract_streams<I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], track: usize, output_file: O, format: ExtractFormat, progress_cb: &mut dyn FnMut(ProgressEvent)) -> Result<()> {
+    let scan = scan_files(files, &MergeOptions::default(), progress_cb)?;
+    let insta360_max_read = scan.insta360_max_read;
+    let usable = scan.usable.clone();
+    let table = SampleTable::from_scan(scan)?;
+    if track >= table.track_count() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Track {track} doesn't exist")));
+    }
+    let ranges = table.chunk_ranges(track);
+    let inputs = usable.iter().enumerate().filter(|x| *x.1).map(|x| x.0).collect();
+    let files = &mut files.iter_mut().zip(&usable).filter(|x| *x.1).map(|(x, _)| (&mut x.0, x.1)).collect::<Vec<_>>();
+
+    let mut progress = Progress::new(progress_cb);
+    progress.set_media(&ranges.iter().map(|x| (Some(x.0), x.1, x.2)).collect::<Vec<_>>(), inputs);
+    let mut f_out = std::io::BufWriter::with_capacity(64*1024, output_file);
+
+    match format {
+        ExtractFormat::Raw => {
+            for (file, offset, size) in &ranges {
+                let f = &mut files.get_mut(*file).ok_or_else(|| crate::invalid_data("Invalid chunk"))?.0;
+                f.seek(SeekFrom::Start(*offset))?;
+                std::io::copy(&mut MediaRead { inner: f.take(*size), file: *file, progress: &mut progress, cancel: None }, &mut f_out)?;
+            }
+        },
+        ExtractFormat::Mp4 => {
+            let mut desc = table.desc;
+            // The new mdat consists of just the chunks of this track
+            let mut offset = 0;
+            for (i, t) in desc.moov_tracks.iter_mut().enumerate() {
+                if i == track {
+                    t.stco = ranges.iter().map(|x| { offset += x.2; offset - x.2 }).collect();
+                } else {
+                    t.excluded = true;
+                    t.stco.clear();
+                }
+            }
+            desc.moov_mvhd_duration = desc.moov_tracks[track].tkhd_duration;
+            desc.mdat_position = ranges.iter().map(|x| (Some(x.0), x.1, x.2)).collect();
+
+            writer::get_first(files)?.seek(SeekFrom::Start(0))?;
+            writer::rewrite_from_desc(files, &mut f_out, &mut desc, &mut progress, 0, insta360_max_read.unwrap_or(u64::MAX))?;
+            writer::patch_chunk_offsets(&mut f_out, &desc)?;
+        }
+    }
+    f_out.flush()?;
+
+    progress.event(ProgressEvent::Finished);
+
+    Ok(())
+}
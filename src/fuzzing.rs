@@ -0,0 +1,25 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Entry points for the fuzz targets in `fuzz/`, only built with `--cfg fuzzing`
+
+use std::io::Cursor;
+use crate::{ desc_reader, insta360, walk_boxes };
+
+pub fn read_boxes(data: &[u8]) {
+    if let Ok(iter) = walk_boxes(&mut Cursor::new(data)) {
+        for x in iter { if x.is_err() { break; } }
+    }
+}
+
+pub fn read_desc(data: &[u8]) {
+    let mut desc = desc_reader::Desc::default();
+    let _ = desc_reader::read_file_desc(&mut Cursor::new(data), data.len(), &mut desc, 0);
+}
+
+pub fn insta360_trailer(data: &[u8]) {
+    let mut files = [(Cursor::new(data), data.len())];
+    if let Ok(offsets) = insta360::get_insta360_offsets(&mut files) {
+        let _ = insta360::merge_metadata(&mut files, &offsets, Cursor::new(Vec::new()), None);
+    }
+}
@@ -1,23 +1,30 @@
 use std::{collections::BTreeMap, io::*};
 use byteorder::{ LittleEndian, ReadBytesExt, WriteBytesExt };
-use crate::writer::get_first;
+use crate::{ invalid_data, writer::get_first, cancel::{ self, CancellationToken } };
+
+/// Record offset -> (data version, record id, format, size)
+pub type Offsets = BTreeMap<u64, (u32, u8, u8, i64)>;
 
 pub const HEADER_SIZE: usize = 32 + 4 + 4 + 32; // padding(32), size(4), version(4), magic(32)
 pub const MAGIC: &[u8] = b"8db42d694ccc418790edff439fe026bf";
 
-pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec<BTreeMap<u64, (u32, u8, u8, i64)>>> {
+pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<Vec<Offsets>> {
     let mut ret = Vec::new();
     for (ref mut stream, size) in files {
         let mut stream = std::io::BufReader::with_capacity(16*1024, stream);
 
+        let mut offsets = BTreeMap::new();
+        if *size < HEADER_SIZE {
+            ret.push(offsets);
+            continue;
+        }
         let mut buf = vec![0u8; HEADER_SIZE];
         stream.seek(SeekFrom::End(-(HEADER_SIZE as i64)))?;
         stream.read_exact(&mut buf)?;
-        let mut offsets = BTreeMap::new();
         if &buf[HEADER_SIZE-32..] == MAGIC {
             let extra_size = (&buf[32..]).read_u32::<LittleEndian>()? as i64;
             let data_version = (&buf[36..]).read_u32::<LittleEndian>()?;
-            let extra_start  = *size - extra_size as usize;
+            let extra_start  = size.checked_sub(extra_size as usize).ok_or_else(|| invalid_data("Invalid Insta360 trailer size"))?;
 
             let mut offset = (HEADER_SIZE + 4+1+1) as i64;
 
@@ -25,6 +32,7 @@ pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<
             let first_id = stream.read_u8()?;
             if first_id == 0 { // record::RecordType::Offsets
                 let size = stream.read_u32::<LittleEndian>()? as i64;
+                if size > extra_size { return Err(invalid_data("Invalid Insta360 offsets record")); }
                 buf.resize(size as usize, 0);
                 stream.seek(SeekFrom::End(-offset - size))?;
                 stream.read_exact(&mut buf)?;
@@ -50,8 +58,7 @@ pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<
                     let format = stream.read_u8()?;
                     let id     = stream.read_u8()?;
                     let size   = stream.read_u32::<LittleEndian>()? as i64;
-
-                    buf.resize(size as usize, 0);
+                    if offset + size > extra_size { return Err(invalid_data("Invalid Insta360 record size")); }
 
                     stream.seek(SeekFrom::End(-offset - size))?;
                     if id > 0 {
@@ -67,15 +74,18 @@ pub fn get_insta360_offsets<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<
     Ok(ret)
 }
 
-pub fn merge_metadata<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], offsets: &[BTreeMap<u64, (u32, u8, u8, i64)>], mut f_out: W) -> Result<()> {
-    assert_eq!(files.len(), offsets.len());
+pub fn merge_metadata<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], offsets: &[Offsets], mut f_out: W, cancel: Option<&CancellationToken>) -> Result<()> {
+    if files.len() != offsets.len() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Offsets don't match the input files"));
+    }
 
     let mut total_size = 0;
     let mut data_version = 3;
 
-    for (offset, (ver, id, format, size)) in offsets.first().unwrap() {
+    for (offset, (ver, id, format, size)) in offsets.first().ok_or_else(|| invalid_data("No input files"))? {
+        cancel::check(cancel)?;
         data_version = *ver;
-        let first_stream = get_first(files);
+        let first_stream = get_first(files)?;
         first_stream.seek(SeekFrom::Start(*offset))?;
         std::io::copy(&mut first_stream.take(*size as u64), &mut f_out)?;
 
@@ -93,10 +103,11 @@ pub fn merge_metadata<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)],
                 if file_i == 0 { continue; }
                 for (offset, (_ver, id, _format, size)) in map {
                     if id2 == *id {
-                        let stream_i = files.get_mut(file_i).map(|x| &mut x.0).unwrap();
+                        cancel::check(cancel)?;
+                        let stream_i = files.get_mut(file_i).map(|x| &mut x.0).ok_or_else(|| invalid_data("Invalid file index"))?;
                         stream_i.seek(SeekFrom::Start(*offset))?;
                         std::io::copy(&mut stream_i.take(*size as u64), &mut f_out)?;
-                        size2 += *size as i64;
+                        size2 += *size;
                     }
                 }
             }
@@ -109,9 +120,9 @@ pub fn merge_metadata<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)],
 
     f_out.write_u128::<LittleEndian>(0)?; // padding
     f_out.write_u128::<LittleEndian>(0)?; // padding
-    f_out.write_u32::<LittleEndian>(total_size as u32 + 72)?;
+    f_out.write_u32::<LittleEndian>((total_size + 72) as u32)?;
     f_out.write_u32::<LittleEndian>(data_version)?; // version
-    f_out.write(MAGIC)?;
+    f_out.write_all(MAGIC)?;
 
     Ok(())
 }
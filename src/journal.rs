@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// The journal is a small text file describing the merge (input sizes and the media data ranges to copy), followed by
+// the number of bytes at the start of the output which are completely written:
+//   mp4-merge journal 1
+//   inputs: SIZE SIZE ...
+//   mdat: FILE:OFFSET:SIZE FILE:OFFSET:SIZE ...
+//   written: BYTES
+// When a merge is resumed, everything before the merged media data is written again, and the media data continues
+// from where the journal says it ended, after all of the media data before it was compared with the inputs.
+// The journal is replaced atomically, after the output is synced to disk when it's a file.
+
+use std::fs::File;
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+use std::path::{ Path, PathBuf };
+use std::time::Instant;
+use byteorder::{ BigEndian, ReadBytesExt };
+use crate::{ fourcc, desc_reader::Desc };
+
+// Size of the parts of the written media data compared with the inputs at once
+const VERIFY_BUFFER_SIZE: usize = 1024*1024;
+
+#[derive(Clone)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    header: String,
+}
+
+impl Journal {
+    pub fn new(path: &Path, sizes: impl Iterator<Item = usize>, desc: &Desc) -> Self {
+        let sizes = sizes.map(|x| x.to_string()).collect::<Vec<_>>().join(" ");
+        let mdat = desc.mdat_position.iter().map(|(file, offset, size)| format!("{}:{offset}:{size}", file.map(|x| x as i64).unwrap_or(-1))).collect::<Vec<_>>().join(" ");
+        Self { path: path.to_owned(), header: format!("mp4-merge journal 1\ninputs: {sizes}\nmdat: {mdat}\n") }
+    }
+
+    /// Returns the output position up to which the output written by an interrupted merge can be reused, and the number of media data bytes before it.
+    /// Returns zeros if there's no journal, it describes a different merge, or the written data doesn't match the inputs.
+    pub fn resume<I: Read + Seek, O: Read + Seek>(&self, files: &mut [(&mut I, usize)], output: &mut O, desc: &Desc) -> Result<(u64, u64)> {
+        let Ok(journal) = std::fs::read_to_string(&self.path) else { return Ok((0, 0)); };
+        let written = journal.strip_prefix(&self.header).and_then(|x| x.trim().strip_prefix("written: ")?.parse::<u64>().ok());
+        let Some(written) = written else {
+            log::warn!("Journal {} doesn't match the inputs, starting over", self.path.display());
+            return Ok((0, 0));
+        };
+        let Some(data_start) = find_mdat_payload(output)? else { return Ok((0, 0)); };
+        let done = written.saturating_sub(data_start).min(desc.mdat_offset);
+        if done == 0 || output.seek(SeekFrom::End(0))? < data_start + done {
+            return Ok((0, 0));
+        }
+
+        // Compare all of the written media data with the inputs
+        let mut buf = (vec![0u8; VERIFY_BUFFER_SIZE], vec![0u8; VERIFY_BUFFER_SIZE]);
+        let mut merged_offset = 0;
+        for (file_index, offset, size) in &desc.mdat_position {
+            let Some(f) = file_index.and_then(|x| files.get_mut(x)) else { continue; };
+            let mut len = done.saturating_sub(merged_offset).min(*size);
+            if len == 0 { break; }
+            f.0.seek(SeekFrom::Start(*offset))?;
+            output.seek(SeekFrom::Start(data_start + merged_offset))?;
+            while len > 0 {
+                let n = len.min(VERIFY_BUFFER_SIZE as u64) as usize;
+                f.0.read_exact(&mut buf.0[..n])?;
+                output.read_exact(&mut buf.1[..n])?;
+                if buf.0[..n] != buf.1[..n] {
+                    log::warn!("The output doesn't match the inputs, starting over");
+                    return Ok((0, 0));
+                }
+                len -= n as u64;
+            }
+            merged_offset += size;
+        }
+        log::info!("Resuming after {done} bytes of media data");
+        Ok((data_start + done, done))
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(".tmp");
+        self.path.with_file_name(name)
+    }
+
+    /// Replaces the journal with the new `written`, through a temporary file, so it's either the old or the new one after a crash
+    fn write(&self, written: u64) -> Result<()> {
+        let temp = self.temp_path();
+        let mut f = File::create(&temp)?;
+        f.write_all(format!("{}written: {written}\n", self.header).as_bytes())?;
+        f.sync_data()?;
+        std::fs::rename(&temp, &self.path)
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        let _ = std::fs::remove_file(self.temp_path());
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+            _ => Ok(())
+        }
+    }
+}
+
+/// Offset of the `mdat` payload in a partially written output. Its size isn't set yet, so the header is always 16 bytes.
+fn find_mdat_payload<R: Read + Seek>(output: &mut R) -> Result<Option<u64>> {
+    let end = output.seek(SeekFrom::End(0))?;
+    let mut offs = 0;
+    while offs + 16 <= end {
+        output.seek(SeekFrom::Start(offs))?;
+        let size = output.read_u32::<BigEndian>()? as u64;
+        let typ = output.read_u32::<BigEndian>()?;
+        if typ == fourcc("mdat") {
+            return Ok((size == 1).then_some(offs + 16));
+        }
+        let size = if size == 1 { output.read_u64::<BigEndian>()? } else { size };
+        if size < 8 { break; }
+        offs += size;
+    }
+    Ok(None)
+}
+
+/// Output stream which records how much of the output is written, see [`Journal`]. Passes everything through without a journal.
+pub(crate) struct JournalStream<W: Read + Write + Seek> {
+    inner: W,
+    journal: Option<Journal>,
+    file: Option<File>, // behind `inner`, synced before the journal is updated
+    pos: u64,
+    written: u64, // end of the contiguous data written from the start of the output
+    last_update: Instant,
+}
+
+impl<W: Read + Write + Seek> JournalStream<W> {
+    pub fn new(inner: W, journal: Option<Journal>, file: Option<File>, written: u64) -> Self {
+        Self { inner, journal, file, pos: 0, written, last_update: Instant::now() }
+    }
+
+    fn update(&mut self) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            // The journal must not claim data which isn't on disk yet
+            self.inner.flush()?;
+            if let Some(file) = &self.file {
+                file.sync_data()?;
+            }
+            journal.write(self.written)?;
+        }
+        self.last_update = Instant::now();
+        Ok(())
+    }
+}
+
+impl<W: Read + Write + Seek> Read for JournalStream<W> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+impl<W: Read + Write + Seek> Seek for JournalStream<W> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.pos = self.inner.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+impl<W: Read + Write + Seek> Write for JournalStream<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.inner.write(buf)?;
+        if self.pos <= self.written {
+            self.written = self.written.max(self.pos + written as u64);
+        }
+        self.pos += written as u64;
+        if self.journal.is_some() && self.last_update.elapsed().as_secs() >= 1 {
+            self.update()?;
+        }
+        Ok(written)
+    }
+    fn flush(&mut self) -> Result<()> { self.inner.flush() }
+}
@@ -0,0 +1,62 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fs::File;
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+use std::path::PathBuf;
+use crate::{ cancel, CancellationToken, progress::Progress };
+
+// Small enough for a responsive progress and cancellation. Reflinks also need the source and destination offsets to be block aligned,
+// which they usually aren't, as the media data follows the mdat header; the kernel copies the data then.
+const CHUNK_SIZE: u64 = 16*1024*1024;
+
+/// Files behind the input and output streams, when they are known to be plain files
+#[derive(Debug)]
+pub(crate) struct FileHandles {
+    pub inputs: Vec<Option<PathBuf>>, // of each merged file, opened for each copy in the slot reserved in the pool, see `Pool::reserve`
+    pub output: Option<File>,
+}
+
+impl FileHandles {
+    pub fn input(&self, file_index: usize) -> Option<File> {
+        File::open(self.inputs.get(file_index)?.as_ref()?).ok()
+    }
+}
+
+/// Copies `size` bytes at `offset` of merged file `file_index` to the current position of `output` without going through user-space
+/// buffers: `std::io::copy` between files uses `copy_file_range` on Linux, which reflinks on Btrfs and XFS. Returns false
+/// unless both are plain files on the same device, otherwise a pipelined copy is faster than the kernel's fallback.
+pub(crate) fn copy<W: Write + Seek>(handles: Option<&FileHandles>, (file_index, offset, size): (usize, u64, u64), output: &mut W, progress: &mut Progress, cancel: Option<&CancellationToken>) -> Result<bool> {
+    let Some((mut input, mut output_file)) = handles.and_then(|x| Some((x.output.as_ref().and(x.input(file_index))?, x.output.as_ref()?))) else {
+        return Ok(false);
+    };
+    if !same_device(&input, output_file) {
+        return Ok(false);
+    }
+    // The output handle shares the position with the stream
+    output.flush()?;
+    let out_pos = output.stream_position()?;
+    output_file.seek(SeekFrom::Start(out_pos))?;
+    input.seek(SeekFrom::Start(offset))?;
+
+    let mut done = 0;
+    while done < size {
+        cancel::check(cancel)?;
+        let copied = std::io::copy(&mut (&input).take((size - done).min(CHUNK_SIZE)), &mut output_file)?;
+        if copied == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "The media data ends before the size in mdat"));
+        }
+        progress.copied(file_index, copied);
+        done += copied;
+    }
+    output.seek(SeekFrom::Start(out_pos + size))?;
+    Ok(true)
+}
+
+#[cfg(unix)]
+fn same_device(a: &File, b: &File) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    a.metadata().ok().zip(b.metadata().ok()).is_some_and(|(a, b)| a.dev() == b.dev())
+}
+#[cfg(not(unix))]
+fn same_device(_: &File, _: &File) -> bool { false }
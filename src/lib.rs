@@ -1,16 +1,60 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
-use std::io::{ Read, Seek, Write, Result };
+use std::io::{ Read, Seek, SeekFrom, Write, Result };
 use std::path::*;
-use byteorder::{ BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt };
 use std::time::Instant;
 
 mod desc_reader;
-mod progress_stream;
+mod progress;
 mod writer;
 mod insta360;
-use progress_stream::*;
+mod recovery;
+mod probe;
+mod box_tree;
+mod samples;
+mod extract;
+mod report;
+mod provenance;
+mod split;
+mod trim;
+mod append;
+mod journal;
+mod output;
+mod verify;
+mod checksum;
+mod merger;
+mod cancel;
+mod kernel_copy;
+mod pipeline;
+mod streaming;
+mod provider;
+#[cfg(feature = "tokio")]
+mod async_merge;
+pub use recovery::{ RecoveredStream, recover_stream, has_moov };
+pub use probe::{ probe, probe_file_streams, ProbeInfo, TrackInfo };
+pub use box_tree::{ BoxHeader, BoxEntry, BoxIter, walk_boxes };
+pub use samples::{ sample_table, sample_table_file_streams, SampleTable, SampleInfo, TrackSamples };
+pub use extract::{ extract_track, extract_track_streams, ExtractFormat };
+pub use report::{ MergeReport, SkippedInput, InputReport, TrackBoundary };
+pub use provenance::{ read_provenance, Provenance, SourceInfo, PROVENANCE_UUID };
+pub use split::{ split_file, split_file_stream, SplitAt };
+pub use trim::TimeRange;
+pub use append::{ append_files, append_file_streams };
+pub use output::{ AtomicOutput, remove_temporary_files };
+pub use verify::{ verify_merge, verify_merge_streams, VerifyMode, VerifyReport };
+pub use checksum::{ ChecksumAlgorithm, to_hex };
+pub use merger::Merger;
+pub use provider::InputProvider;
+pub use progress::ProgressEvent;
+pub use cancel::{ CancellationToken, Cancelled, is_cancelled };
+#[cfg(feature = "tokio")]
+pub use async_merge::{ join_files_async, join_file_streams_async };
+
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzzing;
+use progress::{ Progress, fraction_cb };
 
 // We need to:
 // - Merge mdat boxes
@@ -21,6 +65,7 @@ use progress_stream::*;
 // - Merge lists moov/trak/mdia/minf/stbl/stts
 // - Merge lists moov/trak/mdia/minf/stbl/stsz
 // - Merge lists moov/trak/mdia/minf/stbl/stss
+// - Merge lists moov/trak/mdia/minf/stbl/ctts
 // - Merge lists moov/trak/mdia/minf/stbl/stco and co64
 // - Rewrite stco to co64
 
@@ -40,120 +85,314 @@ fn typ_to_str(typ: u32) -> String {
     }
 }
 
+pub(crate) fn invalid_data<E: Into<Box<dyn std::error::Error + Send + Sync>>>(msg: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a box header, returns `(type, offset, size, header_size)`. See [`BoxHeader::read`]
+///
+/// A box with a size of 0 extends to the end of the stream, and its actual size is returned. Versions up to 0.1.11 returned the 0 as is.
 pub fn read_box<R: Read + Seek>(reader: &mut R) -> Result<(u32, u64, u64, i64)> {
-    let pos = reader.stream_position()?;
-    let size = reader.read_u32::<BigEndian>()?;
-    let typ = reader.read_u32::<BigEndian>()?;
-    if size == 1 {
-        let largesize = reader.read_u64::<BigEndian>()?;
-        Ok((typ, pos, largesize, 16))
-    } else {
-        Ok((typ, pos, size as u64, 8))
-    }
+    let header = BoxHeader::read(reader)?;
+    Ok((header.typ, header.offset, header.size, header.header_size as i64))
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MergeOptions {
+    /// Skip input files which can't be merged (no `mdat`, no `moov`, no samples or inconsistent sample tables)
+    /// instead of failing. Skipped files are listed in [`MergeReport::skipped`].
+    pub tolerant: bool,
+    /// Store the names, sizes, creation times, media data and sample ranges of the inputs in the output `moov`, see [`read_provenance`]
+    pub provenance: bool,
+    /// Names of the inputs for the provenance box. Filled from the paths by [`join_files_with_options`] if empty
+    pub source_names: Vec<String>,
+    /// Keep only these time ranges of the merged file. Each range starts at the sync sample preceding its start, because
+    /// the media data isn't re-encoded. [`MergeReport::inputs`] is empty and provenance isn't written for trimmed outputs.
+    pub keep: Vec<TimeRange>,
+    /// When trimming, hide the samples before the requested starts with an edit list, for frame-accurate cuts in players
+    /// which support it. Only tracks which already have an edit list (`elst`) are updated.
+    pub edit_list: bool,
+    /// Record the progress in this file, and resume an interrupted merge of the same inputs if it exists. The output then has to be
+    /// the partially written output, which is checked against the inputs before continuing. The file is removed when the merge is done.
+    /// When the output is a path, it's synced to disk before each update of the journal, other output streams are only flushed.
+    /// [`join_files_with_options`] keeps the partial output in `.{name}.partial` next to the output, see [`AtomicOutput::resumable`].
+    /// The journal only counts data written through the output stream, so the media data isn't copied in the kernel (`copy_file_range`) then.
+    pub journal: Option<PathBuf>,
+    /// Compute a checksum of the media data of each input and of the whole output while copying it, see [`InputReport::checksum`]
+    /// and [`MergeReport::checksum`]. Available algorithms depend on the enabled cargo features.
+    pub checksum: Option<ChecksumAlgorithm>,
+    /// Write `moov` before `mdat`, so players can start before the whole file is downloaded
+    pub fast_start: bool,
+    /// Keep only these tracks (indices of the merged tracks). Like [`keep`](Self::keep), the media data is sliced,
+    /// so [`MergeReport::inputs`] is empty and provenance isn't written.
+    pub tracks: Option<Vec<usize>>,
+    /// Size of the buffers the media data is copied through: the output buffer, and the two buffers of the copy from input
+    /// paths, which reads the next one in a thread while writing the last one. 0 uses the defaults of 64 KiB and 4 MiB
+    pub buffer_size: usize,
+    /// Inputs from paths or an [`InputProvider`] which are open at the same time, the others are opened when they are read.
+    /// One of them is kept for copying the media data of a path, unless the limit is 1. 0 uses the default of 64
+    pub max_open_files: usize,
+    /// Leave out vendor metadata appended after the last box (Insta360) instead of merging it
+    pub skip_vendor_trailer: bool,
+    /// Stops the merge when cancelled. It then fails with an error for which [`is_cancelled`] is true,
+    /// and [`join_files_with_options`] removes the output (unless it's resumable, see [`journal`](Self::journal)).
+    pub cancel: Option<CancellationToken>,
 }
 
-pub fn join_files<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_file: &P, progress_cb: F) -> Result<()> {
-    let mut open_files = Vec::with_capacity(files.len());
+pub fn join_files<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_file: &P, progress_cb: F) -> Result<MergeReport> {
+    join_files_with_options(files, output_file, &MergeOptions::default(), progress_cb)
+}
+
+pub fn join_files_with_options<P: AsRef<Path>, F: Fn(f64)>(files: &[P], output_file: &P, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    Merger::new().inputs(files).output(output_file).options(options.clone()).progress_fraction(progress_cb).run()
+}
+
+/// Opens `files` for merging, rebuilding the sample tables of chapters which have media data but no `moov` (e.g. the last chapter
+/// after the camera lost power). The nearest healthy chapter is used as the template, unless `reference` is given.
+/// The returned streams can be passed to [`join_file_streams`].
+pub fn recover_files<P: AsRef<Path>>(files: &[P], reference: Option<&P>) -> Result<Vec<(RecoveredStream<std::fs::File>, usize)>> {
+    let mut healthy = Vec::with_capacity(files.len());
     for x in files {
+        healthy.push(has_moov(&mut std::io::BufReader::new(std::fs::File::open(x)?))?);
+    }
+    let mut ret = Vec::with_capacity(files.len());
+    for (i, x) in files.iter().enumerate() {
         let f = std::fs::File::open(x)?;
-        let size = f.metadata()?.len() as usize;
-        open_files.push((f, size));
+        let stream = if healthy[i] {
+            RecoveredStream::passthrough(f)?
+        } else {
+            let template = match reference {
+                Some(reference) => reference.as_ref(),
+                None => {
+                    // Prefer the previous chapter, as it's the closest to the truncated one
+                    let nearest = (0..i).rev().chain(i + 1..files.len()).find(|x| healthy[*x]);
+                    nearest.map(|x| files[x].as_ref()).ok_or_else(|| invalid_data(format!("No healthy file to recover {} from", x.as_ref().display())))?
+                }
+            };
+            log::info!("Recovering {} using {} as the template", x.as_ref().display(), template.display());
+            recover_stream(&mut std::fs::File::open(template)?, f)?
+        };
+        let size = stream.len() as usize;
+        ret.push((stream, size));
     }
-    join_file_streams(&mut open_files, std::fs::File::create(output_file)?, progress_cb)
+    Ok(ret)
+}
+
+pub(crate) struct Scan {
+    pub desc: desc_reader::Desc,
+    pub report: MergeReport,
+    pub usable: Vec<bool>, // files which weren't skipped
+    pub total_size: usize,
+    pub insta360_max_read: Option<u64>,
 }
 
-pub fn join_file_streams<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, progress_cb: F) -> Result<()> {
-    // Get the merged description from all source files
+/// Reads the merged description from all source files
+pub(crate) fn scan_files<I: Read + Seek>(files: &mut [(I, usize)], options: &MergeOptions, progress_cb: &mut dyn FnMut(ProgressEvent)) -> Result<Scan> {
+    if files.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "No input files"));
+    }
+
     let mut desc = desc_reader::Desc::default();
-    desc.moov_tracks.resize(10, Default::default());
+    let mut report = MergeReport::default();
+    let mut usable = vec![false; files.len()];
+    let mut num_usable = 0;
     let mut total_size = 0;
-    let num_files = files.len() as f64;
+    let num_files = files.len();
     let mut insta360_max_read = None;
+    let mut track_times = Vec::new();
     for (i, fs) in files.iter_mut().enumerate() {
         let filesize = fs.1;
         let mut fs = std::io::BufReader::with_capacity(16*1024, &mut fs.0);
-        total_size += filesize;
+        let stts_before = desc.moov_tracks.iter().map(|t| t.stts.len()).collect::<Vec<_>>();
+        let duration_before = desc.moov_mvhd_duration;
 
-        { // Find mdat first
-            while let Ok((typ, offs, size, header_size)) = read_box(&mut fs) {
-                let org_pos = fs.stream_position()?;
-                if typ == fourcc("mdat") {
-                    log::debug!("Reading {}, offset: {}, size: {size}, header_size: {header_size}", typ_to_str(typ), offs);
-                    desc.mdat_position.push((None, org_pos, size - header_size as u64));
-                    desc.mdat_final_position = org_pos;
-                    break;
-                }
-                fs.seek(std::io::SeekFrom::Start(org_pos + size - header_size as u64))?;
-            }
-
-            if insta360_max_read.is_none() {
-                fs.seek(std::io::SeekFrom::End(-40))?;
-                let mut buf = vec![0u8; 40];
-                fs.read_exact(&mut buf)?;
-                // Check if it's Insta360
-                if &buf[8..] == insta360::MAGIC {
-                    insta360_max_read = Some(filesize as u64 - (&buf[..]).read_u32::<LittleEndian>()? as u64);
-                }
+        cancel::check(options.cancel.as_ref())?;
+        progress_cb(ProgressEvent::Scanning { file: i, files: num_files });
+        let checkpoint = options.tolerant.then(|| desc.checkpoint());
+        let result = desc_reader::read_file_desc(&mut fs, filesize, &mut desc, num_usable).and_then(|insta360_start| {
+            if let Some(checkpoint) = &checkpoint {
+                desc.validate(checkpoint, num_usable)?;
             }
-
-            fs.seek(std::io::SeekFrom::Start(0))?;
+            Ok(insta360_start)
+        });
+        let insta360_start = match (result, checkpoint) {
+            (Ok(x), _) => x,
+            (Err(e), Some(checkpoint)) => {
+                log::warn!("Skipping file {i}: {e}");
+                desc.rollback(checkpoint);
+                report.skipped.push(SkippedInput { index: i, reason: e.to_string() });
+                continue;
+            },
+            (Err(e), None) => return Err(std::io::Error::new(e.kind(), format!("File {i}: {e}")))
+        };
+        if insta360_max_read.is_none() {
+            insta360_max_read = insta360_start;
         }
+        total_size += filesize;
 
-        desc_reader::read_desc(&mut fs, &mut desc, 0, u64::MAX, i)?;
+        track_times.resize(desc.moov_tracks.len(), 0);
+        let tracks = desc.moov_tracks.iter().zip(&mut track_times).enumerate().map(|(ti, (t, time))| {
+            let start_time = *time;
+            *time += t.stts.get(stts_before.get(ti).copied().unwrap_or(0)..).unwrap_or_default().iter().map(|x| x.0 as u64 * x.1 as u64).sum::<u64>();
+            TrackBoundary { first_sample: t.sample_offset, sample_count: t.stsz_count - t.sample_offset, start_time, timescale: t.mdhd_timescale }
+        }).collect();
+        report.inputs.push(InputReport {
+            index: i,
+            start_time: duration_before,
+            duration: desc.moov_mvhd_duration - duration_before,
+            data_offset: desc.mdat_offset, // relative to the merged mdat until it's written
+            data_size: desc.mdat_position.last().map(|x| x.2).unwrap_or(0),
+            checksum: None,
+            tracks
+        });
 
         if let Some(mdat) = desc.mdat_position.last_mut() {
-            mdat.0 = Some(i);
+            mdat.0 = Some(num_usable);
             desc.mdat_offset += mdat.2;
             for t in &mut desc.moov_tracks {
+                t.pad_ctts();
                 t.sample_offset = t.stsz_count;
                 t.chunk_offset = t.stco.len() as u32;
             }
         }
+        usable[i] = true;
+        num_usable += 1;
+    }
+    if num_usable == 0 {
+        return Err(invalid_data("None of the input files can be merged"));
+    }
+    report.timescale = desc.moov_mvhd_timescale;
+    report.duration = desc.moov_mvhd_duration;
+    report.media_data_size = desc.mdat_offset;
+    Ok(Scan { desc, report, usable, total_size, insta360_max_read })
+}
+
+pub fn join_file_streams<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, progress_cb: F) -> Result<MergeReport> {
+    join_file_streams_with_options(files, output_file, &MergeOptions::default(), progress_cb)
+}
+
+pub fn join_file_streams_with_options<F: Fn(f64), I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    merge_streams(files, output_file, options, &mut fraction_cb(&progress_cb), None, None)
+}
+
+/// Like [`join_file_streams_with_options`], but the output is written strictly sequentially, so it doesn't have to be seekable,
+/// e.g. a pipe or stdout. Everything except the media data is built in memory first. [`MergeOptions::journal`] isn't supported.
+pub fn join_file_streams_to_writer<F: Fn(f64), I: Read + Seek, O: Write>(files: &mut [(I, usize)], output_file: O, options: &MergeOptions, progress_cb: F) -> Result<MergeReport> {
+    streaming::merge_to_writer(files, output_file, options, &mut fraction_cb(&progress_cb))
+}
+
+/// Media data which [`merge_streams`] leaves out of the output, see [`streaming::merge_to_writer`]
+#[derive(Default)]
+pub(crate) struct DeferredMedia {
+    pub ranges: Vec<(Option<usize>, u64, u64)>, // merged file, offset, size, in the output order
+    pub inputs: Vec<usize>, // index in the input list of each merged file
+}
+
+/// `handles` are the files behind the streams, if known, see [`kernel_copy`].
+/// With `deferred`, the output only gets a gap for the media data, and checksums aren't computed
+pub(crate) fn merge_streams<I: Read + Seek, O: Read + Write + Seek>(files: &mut [(I, usize)], output_file: O, options: &MergeOptions, progress_cb: &mut dyn FnMut(ProgressEvent), handles: Option<kernel_copy::FileHandles>, deferred: Option<&mut DeferredMedia>) -> Result<MergeReport> {
+    let scan_start = Instant::now();
+    let mut scan = scan_files(files, options, progress_cb)?;
+    let (mut report, usable, insta360_max_read) = (std::mem::take(&mut scan.report), scan.usable.clone(), scan.insta360_max_read);
+    report.scan_time = scan_start.elapsed();
+    let write_start = Instant::now();
 
-        progress_cb(((i as f64 + 1.0) / num_files) * 0.1);
+    let sliced = !options.keep.is_empty() || options.tracks.is_some();
+    let mut desc = if !sliced {
+        scan.desc
+    } else {
+        let desc = trim::trim(&SampleTable::from_scan(scan)?, &report.inputs, &options.keep, options.edit_list, options.tracks.as_deref())?;
+        // The input boundaries don't apply to the sliced output
+        report.inputs.clear();
+        report.duration = desc.moov_mvhd_duration;
+        report.media_data_size = desc.mdat_offset;
+        desc
+    };
+
+    if options.provenance && sliced {
+        log::warn!("Provenance isn't written when trimming or filtering tracks");
+    } else if options.provenance {
+        let provenance = Provenance {
+            timescale: report.timescale,
+            sources: provenance::sources(&report.inputs, &desc, |i| files[i].1 as u64, &options.source_names)
+        };
+        desc.provenance = Some(provenance.to_box());
+    }
+    desc.checksum = options.checksum.filter(|_| deferred.is_none());
+    desc.fast_start = options.fast_start;
+    desc.cancel = options.cancel.clone();
+    let journal = options.journal.as_ref().map(|path| journal::Journal::new(path, files.iter().map(|x| x.1), &desc));
+    let files = &mut files.iter_mut().zip(&usable).filter(|x| *x.1).map(|(x, _)| (&mut x.0, x.1)).collect::<Vec<_>>();
+
+    let mut output_file = output_file;
+    let mut resume_position = 0;
+    if let Some(journal) = &journal {
+        (resume_position, desc.mdat_resume) = journal.resume(files, &mut output_file, &desc)?;
+        output_file.seek(SeekFrom::Start(0))?;
+    }
+    let inputs = usable.iter().enumerate().filter(|x| *x.1).map(|x| x.0).collect::<Vec<_>>();
+    let journal_file = handles.as_ref().and_then(|x| x.output.as_ref()?.try_clone().ok()).filter(|_| journal.is_some());
+    if let Some(handles) = handles {
+        let inputs = handles.inputs.into_iter().zip(&usable).filter(|x| *x.1).map(|x| x.0).collect();
+        // The journal only counts data written through the stream
+        let output = handles.output.filter(|_| journal.is_none());
+        desc.files = Some(std::sync::Arc::new(kernel_copy::FileHandles { inputs, output }));
+        desc.copy_buffer_size = if options.buffer_size > 0 { options.buffer_size } else { 4*1024*1024 };
+    }
+    if let Some(deferred) = deferred {
+        // Skipped like the data of an interrupted merge
+        desc.mdat_resume = u64::MAX;
+        *deferred = DeferredMedia { ranges: desc.mdat_position.clone(), inputs: inputs.clone() };
     }
 
     // Write it to the file
-    let mut debounce = Instant::now();
-    let f_out = ProgressStream::new(output_file, |total| {
-        if (Instant::now() - debounce).as_millis() > 100 {
-            progress_cb((0.1 + ((total as f64 / total_size as f64) * 0.9)).min(0.9999));
-            debounce = Instant::now();
-        }
-    });
-    let mut f_out = std::io::BufWriter::with_capacity(64*1024, f_out);
+    let mut progress = Progress::new(progress_cb);
+    progress.set_media(&desc.mdat_position, inputs);
+    let f_out = journal::JournalStream::new(output_file, journal.clone(), journal_file, resume_position);
+    let buffer_size = if options.buffer_size > 0 { options.buffer_size } else { 64*1024 };
+    let mut f_out = std::io::BufWriter::with_capacity(buffer_size, f_out);
 
-    writer::get_first(files).seek(std::io::SeekFrom::Start(0))?;
-    writer::rewrite_from_desc(files, &mut f_out, &mut desc, 0, insta360_max_read.unwrap_or(u64::MAX))?;
+    writer::get_first(files)?.seek(SeekFrom::Start(0))?;
+    writer::rewrite_from_desc(files, &mut f_out, &mut desc, &mut progress, 0, insta360_max_read.unwrap_or(u64::MAX))?;
+    let mut end = f_out.stream_position()?;
 
     // Patch final mdat positions
-    for track in &desc.moov_tracks {
-        f_out.seek(std::io::SeekFrom::Start(track.co64_final_position))?;
-        for x in &track.stco {
-            f_out.write_u64::<BigEndian>(*x + desc.mdat_final_position)?;
-        }
-    }
+    writer::patch_chunk_offsets(&mut f_out, &desc)?;
 
-    if insta360_max_read.is_some() {
+    if insta360_max_read.is_some() && !options.skip_vendor_trailer {
         // Merge Insta360 metadata
-        f_out.seek(std::io::SeekFrom::End(0))?;
+        progress.event(ProgressEvent::WritingTrailer);
+        f_out.seek(SeekFrom::Start(end))?;
         let offsets = insta360::get_insta360_offsets(files)?;
-        insta360::merge_metadata(files, &offsets, f_out)?;
+        insta360::merge_metadata(files, &offsets, &mut f_out, options.cancel.as_ref())?;
+        end = f_out.stream_position()?;
+    }
+
+    report.output_size = end;
+    f_out.flush()?;
+    if let Some(journal) = &journal {
+        journal.remove()?;
+    }
+    for (i, x) in report.inputs.iter_mut().enumerate() {
+        x.data_offset += desc.mdat_final_position;
+        x.checksum = desc.checksums.get_mut(i).and_then(Option::take);
     }
+    report.checksum = desc.media_checksum.take();
+    report.write_time = write_start.elapsed();
 
-    progress_cb(1.0);
+    progress.event(ProgressEvent::Finished);
 
-    Ok(())
+    Ok(report)
 }
 
 pub fn update_file_times(input_path: &PathBuf, output_path: &PathBuf) {
     if let Err(e) = || -> std::io::Result<()> {
-        let org_time = filetime_creation::FileTime::from_creation_time(&std::fs::metadata(&input_path)?).ok_or(std::io::ErrorKind::Other)?;
+        let org_time = filetime_creation::FileTime::from_creation_time(&std::fs::metadata(input_path)?).ok_or(std::io::ErrorKind::Other)?;
         if cfg!(target_os = "windows") {
-            ::log::debug!("Updating creation time of {} to {}", output_path.display(), org_time.to_string());
+            ::log::debug!("Updating creation time of {} to {}", output_path.display(), org_time);
             filetime_creation::set_file_ctime(output_path, org_time)?;
         } else {
-            ::log::debug!("Updating modification time of {} to {}", output_path.display(), org_time.to_string());
+            ::log::debug!("Updating modification time of {} to {}", output_path.display(), org_time);
             filetime_creation::set_file_mtime(output_path, org_time)?;
         }
         Ok(())
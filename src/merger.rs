@@ -0,0 +1,208 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, Result };
+use std::cell::RefCell;
+use std::path::{ Path, PathBuf };
+use std::rc::Rc;
+use crate::{ merge_streams, streaming, kernel_copy::FileHandles, provider::{ InputProvider, LazyInput, Paths, Pool, ReadSeek, MAX_OPEN_FILES }, AtomicOutput, ProgressEvent, CancellationToken, ChecksumAlgorithm, MergeOptions, MergeReport, TimeRange };
+
+trait ReadWriteSeek: Read + Write + Seek { }
+impl<T: Read + Write + Seek> ReadWriteSeek for T { }
+type AddProvider<'a> = Box<dyn FnOnce(&Rc<RefCell<Pool<'a>>>) -> Result<Vec<(LazyInput<'a>, usize)>> + 'a>;
+
+enum Input<'a> {
+    Path(PathBuf),
+    Stream(Box<dyn ReadSeek + 'a>, usize),
+    Provider(usize, AddProvider<'a>), // number of inputs
+}
+
+enum Output<'a> {
+    Path(PathBuf),
+    Stream(Box<dyn ReadWriteSeek + 'a>),
+    Writer(Box<dyn Write + 'a>),
+}
+
+/// Builder for a merge with all options. Inputs can be paths and streams in any combination, see [`run`](Self::run).
+pub struct Merger<'a> {
+    inputs: Vec<Input<'a>>,
+    output: Option<Output<'a>>,
+    options: MergeOptions,
+    progress: Box<dyn FnMut(ProgressEvent) + 'a>,
+}
+
+impl Default for Merger<'_> {
+    fn default() -> Self {
+        Self { inputs: Vec::new(), output: None, options: MergeOptions::default(), progress: Box::new(|_| { }) }
+    }
+}
+
+impl<'a> Merger<'a> {
+    pub fn new() -> Self { Self::default() }
+
+    pub fn input<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.inputs.push(Input::Path(path.as_ref().to_owned()));
+        self
+    }
+    pub fn inputs<P: AsRef<Path>>(mut self, paths: impl IntoIterator<Item = P>) -> Self {
+        self.inputs.extend(paths.into_iter().map(|x| Input::Path(x.as_ref().to_owned())));
+        self
+    }
+    /// Adds an input stream of `size` bytes
+    pub fn input_stream<S: Read + Seek + 'a>(mut self, stream: S, size: usize) -> Self {
+        self.inputs.push(Input::Stream(Box::new(stream), size));
+        self
+    }
+    /// Adds all inputs of `provider`, which are opened when they are read, see [`MergeOptions::max_open_files`]
+    pub fn input_provider<P: InputProvider + 'a>(mut self, provider: P) -> Self {
+        self.inputs.push(Input::Provider(provider.count(), Box::new(move |pool| Pool::add(pool, provider))));
+        self
+    }
+
+    /// Writes to `path`, through a temporary file which is renamed when complete, see [`AtomicOutput`]
+    pub fn output<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.output = Some(Output::Path(path.as_ref().to_owned()));
+        self
+    }
+    /// Writes to `stream`. It isn't truncated, see [`MergeReport::output_size`]
+    pub fn output_stream<S: Read + Write + Seek + 'a>(mut self, stream: S) -> Self {
+        self.output = Some(Output::Stream(Box::new(stream)));
+        self
+    }
+    /// Writes strictly sequentially to `writer`, e.g. stdout, see [`join_file_streams_to_writer`](crate::join_file_streams_to_writer)
+    pub fn output_writer<W: Write + 'a>(mut self, writer: W) -> Self {
+        self.output = Some(Output::Writer(Box::new(writer)));
+        self
+    }
+
+    /// Called at each stage of the merge, and periodically while copying the media data. See [`ProgressEvent::fraction`] for the overall progress
+    pub fn progress<F: FnMut(ProgressEvent) + 'a>(mut self, progress_cb: F) -> Self {
+        self.progress = Box::new(progress_cb);
+        self
+    }
+    // For the callbacks of `join_files`, which only get the overall fraction
+    pub(crate) fn progress_fraction<F: Fn(f64) + 'a>(mut self, progress_cb: F) -> Self {
+        self.progress = Box::new(move |event: ProgressEvent| if let Some(x) = event.fraction() { progress_cb(x) });
+        self
+    }
+
+    /// Replaces all options set so far
+    pub fn options(mut self, options: MergeOptions) -> Self {
+        self.options = options;
+        self
+    }
+    /// See [`MergeOptions::tolerant`]
+    pub fn tolerant(mut self, tolerant: bool) -> Self {
+        self.options.tolerant = tolerant;
+        self
+    }
+    /// See [`MergeOptions::provenance`]
+    pub fn provenance(mut self, provenance: bool) -> Self {
+        self.options.provenance = provenance;
+        self
+    }
+    /// See [`MergeOptions::keep`] and [`MergeOptions::edit_list`]
+    pub fn keep(mut self, ranges: impl IntoIterator<Item = TimeRange>, edit_list: bool) -> Self {
+        self.options.keep = ranges.into_iter().collect();
+        self.options.edit_list = edit_list;
+        self
+    }
+    /// See [`MergeOptions::tracks`]
+    pub fn tracks(mut self, tracks: impl IntoIterator<Item = usize>) -> Self {
+        self.options.tracks = Some(tracks.into_iter().collect());
+        self
+    }
+    /// See [`MergeOptions::journal`]
+    pub fn journal<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.options.journal = Some(path.as_ref().to_owned());
+        self
+    }
+    /// See [`MergeOptions::checksum`]
+    pub fn checksum(mut self, algorithm: ChecksumAlgorithm) -> Self {
+        self.options.checksum = Some(algorithm);
+        self
+    }
+    /// See [`MergeOptions::fast_start`]
+    pub fn fast_start(mut self, fast_start: bool) -> Self {
+        self.options.fast_start = fast_start;
+        self
+    }
+    /// See [`MergeOptions::buffer_size`]
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.options.buffer_size = size;
+        self
+    }
+    /// See [`MergeOptions::max_open_files`]
+    pub fn max_open_files(mut self, max: usize) -> Self {
+        self.options.max_open_files = max;
+        self
+    }
+    /// See [`MergeOptions::skip_vendor_trailer`]
+    pub fn skip_vendor_trailer(mut self, skip: bool) -> Self {
+        self.options.skip_vendor_trailer = skip;
+        self
+    }
+
+    /// See [`MergeOptions::cancel`]
+    pub fn cancel(mut self, token: CancellationToken) -> Self {
+        self.options.cancel = Some(token);
+        self
+    }
+
+    /// Merges the inputs into the output. Fails if no output was set
+    pub fn run(self) -> Result<MergeReport> {
+        let Merger { inputs, output, mut options, mut progress } = self;
+        let output = output.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "No output"))?;
+
+        let paths = inputs.iter().filter_map(|x| match x { Input::Path(p) => Some(p.clone()), _ => None }).collect::<Vec<_>>();
+        if options.provenance && options.source_names.is_empty() {
+            options.source_names = inputs.iter().flat_map(|x| match x {
+                Input::Path(p) => vec![p.file_name().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default()],
+                Input::Stream(..) => vec![String::new()],
+                Input::Provider(count, _) => vec![String::new(); *count]
+            }).collect();
+        }
+        // The paths and providers are opened when they are read, so that only `max_open_files` of them are open at once
+        let pool = Pool::new(if options.max_open_files > 0 { options.max_open_files } else { MAX_OPEN_FILES });
+        let mut streams = Vec::with_capacity(inputs.len());
+        let mut input_files = Vec::with_capacity(inputs.len());
+        for x in inputs {
+            let lazy = match x {
+                Input::Path(path) => {
+                    input_files.push(Some(path.clone()));
+                    Pool::add(&pool, Paths(vec![path]))?
+                },
+                Input::Stream(stream, size) => { input_files.push(None); streams.push((stream, size)); continue; },
+                Input::Provider(count, add) => {
+                    input_files.extend((0..count).map(|_| None));
+                    add(&pool)?
+                }
+            };
+            streams.extend(lazy.into_iter().map(|(x, size)| (Box::new(x) as Box<dyn ReadSeek>, size)));
+        }
+
+        // The copies of the media data open the input paths in a slot of the pool. The sequential writer doesn't use them
+        let inputs_count = input_files.len();
+        let copy_paths = !matches!(output, Output::Writer(_)) && input_files.iter().any(Option::is_some);
+        let input_files = if copy_paths && pool.borrow_mut().reserve() { input_files } else { vec![None; inputs_count] };
+
+        match output {
+            Output::Path(path) => {
+                let mut output = if options.journal.is_some() {
+                    AtomicOutput::resumable(&path, &paths)?
+                } else {
+                    AtomicOutput::create(&path, &paths)?
+                };
+                let handles = FileHandles { inputs: input_files, output: output.file().try_clone().ok() };
+                let report = merge_streams(&mut streams, output.file(), &options, &mut progress, Some(handles), None)?;
+                output.finish(report.output_size)?;
+                Ok(report)
+            },
+            Output::Stream(stream) => {
+                let handles = FileHandles { inputs: input_files, output: None };
+                merge_streams(&mut streams, stream, &options, &mut progress, Some(handles), None)
+            },
+            Output::Writer(writer) => streaming::merge_to_writer(&mut streams, writer, &options, &mut progress)
+        }
+    }
+}
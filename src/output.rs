@@ -0,0 +1,94 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fs::File;
+use std::io::{ Error, ErrorKind, Result };
+use std::path::{ Path, PathBuf };
+use std::sync::Mutex;
+
+// Temporary files being written, removed by `remove_temporary_files`
+static ACTIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Output file which is written under a temporary name in the destination directory and renamed when complete.
+/// The temporary file is removed if it's dropped before [`finish`](Self::finish).
+pub struct AtomicOutput {
+    file: Option<File>,
+    temp_path: PathBuf,
+    path: PathBuf,
+    keep: bool,
+}
+
+impl AtomicOutput {
+    /// Creates the temporary file for `path`. Fails if `path` is one of `inputs`.
+    pub fn create<P: AsRef<Path>>(path: &Path, inputs: &[P]) -> Result<Self> {
+        check_not_input(path, inputs)?;
+        let temp_path = sibling(path, &format!(".{}.tmp", std::process::id()));
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&temp_path)?;
+        ACTIVE.lock().unwrap().push(temp_path.clone());
+        Ok(Self { file: Some(file), temp_path, path: path.to_owned(), keep: false })
+    }
+
+    /// Like [`create`](Self::create), but the temporary file has a fixed name (`.{name}.partial`), is kept when
+    /// the output isn't finished, and is opened without truncating, so an interrupted merge can be resumed.
+    pub fn resumable<P: AsRef<Path>>(path: &Path, inputs: &[P]) -> Result<Self> {
+        check_not_input(path, inputs)?;
+        let temp_path = sibling(path, ".partial");
+        let file = File::options().read(true).write(true).create(true).truncate(false).open(&temp_path)?;
+        Ok(Self { file: Some(file), temp_path, path: path.to_owned(), keep: true })
+    }
+
+    pub fn file(&mut self) -> &mut File { self.file.as_mut().unwrap() }
+    pub fn temp_path(&self) -> &Path { &self.temp_path }
+
+    /// Truncates the output to `size` bytes and renames it to the final name
+    pub fn finish(mut self, size: u64) -> Result<()> {
+        let file = self.file.take().unwrap();
+        file.set_len(size)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&self.temp_path, &self.path)?;
+        self.keep = true;
+        Ok(())
+    }
+}
+
+impl Drop for AtomicOutput {
+    fn drop(&mut self) {
+        drop(self.file.take());
+        if !self.keep {
+            let _ = std::fs::remove_file(&self.temp_path);
+        }
+        ACTIVE.lock().unwrap().retain(|x| *x != self.temp_path);
+    }
+}
+
+/// Removes the temporary files of all outputs being written, e.g. from a Ctrl-C handler before exiting.
+/// Resumable outputs are kept.
+pub fn remove_temporary_files() {
+    for x in ACTIVE.lock().unwrap().drain(..) {
+        let _ = std::fs::remove_file(x);
+    }
+}
+
+/// `.{name}{suffix}` in the same directory as `path`
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+fn check_not_input<P: AsRef<Path>>(path: &Path, inputs: &[P]) -> Result<()> {
+    // The output doesn't exist yet in most cases, so resolve its directory instead
+    let resolve = |p: &Path| -> Option<PathBuf> {
+        p.canonicalize().ok().or_else(|| {
+            let dir = p.parent().filter(|x| !x.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            Some(dir.canonicalize().ok()?.join(p.file_name()?))
+        })
+    };
+    let output = resolve(path);
+    if output.is_some() && inputs.iter().any(|x| resolve(x.as_ref()) == output) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("Output file {} is one of the inputs", path.display())));
+    }
+    Ok(())
+}
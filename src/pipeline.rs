@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+use std::sync::mpsc::sync_channel;
+use crate::{ cancel, CancellationToken, kernel_copy::FileHandles, progress::Progress };
+
+/// Copies `size` bytes at `offset` of merged file `file_index` to `output` through two buffers: a thread reads the next one while the
+/// last one is written, so reading from one device overlaps with writing to another. Returns false if the input isn't a plain file.
+pub(crate) fn copy<W: Write>(handles: Option<&FileHandles>, (file_index, offset, size): (usize, u64, u64), output: &mut W, buffer_size: usize, progress: &mut Progress, cancel: Option<&CancellationToken>) -> Result<bool> {
+    let Some(mut input) = handles.and_then(|x| x.input(file_index)) else {
+        return Ok(false);
+    };
+    input.seek(SeekFrom::Start(offset))?;
+
+    std::thread::scope(|s| {
+        // Filled buffers go to the writer, and come back empty to the reader. Dropping either end stops the other.
+        let (filled_tx, filled_rx) = sync_channel::<Result<Vec<u8>>>(1);
+        let (empty_tx, empty_rx) = sync_channel::<Vec<u8>>(2);
+        for _ in 0..2 { let _ = empty_tx.send(Vec::new()); }
+
+        s.spawn(move || {
+            let mut left = size;
+            while left > 0 {
+                let Ok(mut buf) = empty_rx.recv() else { return; };
+                buf.resize(left.min(buffer_size as u64) as usize, 0);
+                let read = input.read_exact(&mut buf).map(|_| buf);
+                let failed = read.is_err();
+                if filled_tx.send(read).is_err() || failed { return; }
+                left -= left.min(buffer_size as u64);
+            }
+        });
+
+        let mut done = 0;
+        while done < size {
+            cancel::check(cancel)?;
+            let buf = filled_rx.recv().map_err(|_| std::io::Error::other("The reading thread stopped"))??;
+            output.write_all(&buf)?;
+            progress.copied(file_index, buf.len() as u64);
+            done += buf.len() as u64;
+            let _ = empty_tx.send(buf);
+        }
+        Ok(true)
+    })
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result };
+use std::path::Path;
+use crate::{ fourcc, typ_to_str, scan_files, MergeOptions, desc_reader::TrackDesc };
+
+/// Stream information of a file, or of the result of merging several files
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ProbeInfo {
+    /// Number of input files described
+    pub files: usize,
+    /// Movie timescale (units per second) from `mvhd`
+    pub timescale: u32,
+    /// Movie duration in `timescale` units
+    pub duration: u64,
+    /// Total size of the media data (`mdat` payload) in bytes
+    pub media_data_size: u64,
+    pub tracks: Vec<TrackInfo>,
+}
+
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct TrackInfo {
+    /// Handler type, e.g. `vide`, `soun`, `meta` or `tmcd`
+    pub handler: String,
+    /// Fourcc of the first sample entry, e.g. `avc1`, `hvc1`, `mp4a`
+    pub codec: String,
+    /// Frame dimensions, 0 for non-video tracks
+    pub width: u16,
+    pub height: u16,
+    /// Audio sample rate in Hz, 0 for non-audio tracks
+    pub sample_rate: u32,
+    /// Media timescale (units per second) from `mdhd`
+    pub timescale: u32,
+    /// Media duration in `timescale` units
+    pub duration: u64,
+    pub sample_count: u32,
+    /// Number of sync samples. Equal to `sample_count` if the track has no `stss`, i.e. every sample is a sync sample
+    pub keyframe_count: u32,
+    /// Total size of the samples in bytes
+    pub media_data_size: u64,
+    /// Whether the track is concatenated when merging. Tracks which aren't (e.g. timecode) only describe the first file
+    pub merged: bool,
+}
+
+impl ProbeInfo {
+    pub fn duration_secs(&self) -> f64 {
+        if self.timescale == 0 { 0.0 } else { self.duration as f64 / self.timescale as f64 }
+    }
+}
+impl TrackInfo {
+    pub fn duration_secs(&self) -> f64 {
+        if self.timescale == 0 { 0.0 } else { self.duration as f64 / self.timescale as f64 }
+    }
+}
+
+/// Reads the stream information of `files`. A single file describes that file, multiple files describe the merged output.
+pub fn probe<P: AsRef<Path>>(files: &[P]) -> Result<ProbeInfo> {
+    let mut open_files = Vec::with_capacity(files.len());
+    for x in files {
+        let f = std::fs::File::open(x)?;
+        let size = f.metadata()?.len() as usize;
+        open_files.push((f, size));
+    }
+    probe_file_streams(&mut open_files)
+}
+
+pub fn probe_file_streams<I: Read + Seek>(files: &mut [(I, usize)]) -> Result<ProbeInfo> {
+    let scan = scan_files(files, &MergeOptions::default(), &mut |_| { })?;
+    let desc = &scan.desc;
+    Ok(ProbeInfo {
+        files: files.len(),
+        timescale: desc.moov_mvhd_timescale,
+        duration: desc.moov_mvhd_duration,
+        media_data_size: desc.mdat_position.iter().map(|x| x.2).sum(),
+        tracks: desc.moov_tracks.iter().map(track_info).collect(),
+    })
+}
+
+fn track_info(t: &TrackDesc) -> TrackInfo {
+    let media_data_size = if t.stsz_sample_size != 0 { t.stsz_sample_size as u64 * t.stsz_count as u64 }
+                          else                       { t.stsz.iter().map(|x| *x as u64).sum() };
+    TrackInfo {
+        handler: if t.handler != 0 { typ_to_str(t.handler) } else { String::new() },
+        codec: if t.codec != 0 { typ_to_str(t.codec) } else { String::new() },
+        width: t.width,
+        height: t.height,
+        sample_rate: if t.handler == fourcc("soun") && t.sample_rate == 0 { t.mdhd_timescale } else { t.sample_rate },
+        timescale: t.mdhd_timescale,
+        duration: t.mdhd_duration,
+        sample_count: t.stsz_count,
+        keyframe_count: if t.stss.is_empty() { t.stsz_count } else { t.stss.len() as u32 },
+        media_data_size,
+        merged: !t.skip,
+    }
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Result };
+use std::time::{ Duration, Instant };
+use crate::{ cancel, CancellationToken };
+
+/// Stage of a merge, reported to the callback of [`Merger::progress`](crate::Merger::progress)
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProgressEvent {
+    /// Reading the description of input `file` (index in the input list) of `files`
+    Scanning { file: usize, files: usize },
+    /// Copying the media data of input `file`. `done` and `total` are bytes of the whole media data, `file_done`
+    /// and `file_total` of this input. The speed and the estimated time left are measured from the start of the copy.
+    Copying { file: usize, file_done: u64, file_total: u64, done: u64, total: u64, bytes_per_sec: f64, eta: Option<Duration> },
+    /// Writing the sample tables (`moov`)
+    WritingTables,
+    /// Merging vendor metadata after the last box (Insta360)
+    WritingTrailer,
+    Finished,
+}
+
+impl ProgressEvent {
+    /// Overall progress from 0.0 to 1.0, as passed to the callbacks of [`join_files`](crate::join_files) and similar functions.
+    /// Scanning is the first 10%, copying the rest. `None` for stages without a measurable progress.
+    pub fn fraction(&self) -> Option<f64> {
+        match self {
+            Self::Scanning { file, files } => Some(*file as f64 / (*files).max(1) as f64 * 0.1),
+            Self::Copying { done, total, .. } => Some((0.1 + *done as f64 / (*total).max(1) as f64 * 0.9).min(0.9999)),
+            Self::Finished => Some(1.0),
+            _ => None
+        }
+    }
+}
+
+/// Adapts a callback of the overall progress, see [`ProgressEvent::fraction`]
+pub(crate) fn fraction_cb<F: Fn(f64)>(progress_cb: &F) -> impl FnMut(ProgressEvent) + '_ {
+    move |event| if let Some(x) = event.fraction() { progress_cb(x) }
+}
+
+/// Sends events to `cb` with the progress of the media data copy. The callback is generic for the async merge, which has to stay `Send`
+pub(crate) struct Progress<'a, C: FnMut(ProgressEvent) + ?Sized = dyn FnMut(ProgressEvent) + 'a> {
+    cb: &'a mut C,
+    inputs: Vec<usize>, // index in the input list of each merged file
+    file_totals: Vec<u64>, // media data of each merged file
+    file_done: Vec<u64>,
+    total: u64,
+    done: u64,
+    resumed: u64, // copied by an earlier, interrupted merge, see `skipped`
+    start: Instant,
+    last_event: Option<Instant>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(cb: &'a mut dyn FnMut(ProgressEvent)) -> Self {
+        Self::with_callback(cb)
+    }
+}
+
+impl<'a, C: FnMut(ProgressEvent) + ?Sized> Progress<'a, C> {
+    pub fn with_callback(cb: &'a mut C) -> Self {
+        Self { cb, inputs: Vec::new(), file_totals: Vec::new(), file_done: Vec::new(), total: 0, done: 0, resumed: 0, start: Instant::now(), last_event: None }
+    }
+
+    /// Sets the media data to copy: `(file, offset, size)` ranges of the merged files, `inputs` maps them to the input list
+    pub fn set_media(&mut self, mdat_position: &[(Option<usize>, u64, u64)], inputs: Vec<usize>) {
+        self.file_totals = vec![0; inputs.len()];
+        for (file, _, size) in mdat_position {
+            if let Some(x) = file.and_then(|x| self.file_totals.get_mut(x)) { *x += size; }
+        }
+        self.file_done = vec![0; inputs.len()];
+        self.inputs = inputs;
+        self.total = self.file_totals.iter().sum();
+    }
+
+    pub fn event(&mut self, event: ProgressEvent) {
+        (self.cb)(event);
+    }
+
+    /// Media data of `file` which is already in the output and isn't read
+    pub fn skipped(&mut self, file: usize, size: u64) {
+        self.resumed += size;
+        self.done += size;
+        if let Some(x) = self.file_done.get_mut(file) { *x += size; }
+    }
+
+    pub fn copied(&mut self, file: usize, size: u64) {
+        if size == 0 { return; }
+        let last_event = *self.last_event.get_or_insert_with(|| { self.start = Instant::now(); self.start });
+        self.done += size;
+        let Some(file_done) = self.file_done.get_mut(file) else { return; };
+        *file_done += size;
+        let file_done = *file_done;
+        let file_total = self.file_totals[file];
+        // At most every 100 ms, and at the end of each file
+        if last_event.elapsed().as_millis() < 100 && file_done < file_total { return; }
+        self.last_event = Some(Instant::now());
+
+        let copied = self.done.saturating_sub(self.resumed);
+        let bytes_per_sec = copied as f64 / self.start.elapsed().as_secs_f64().max(0.001);
+        let eta = (bytes_per_sec > 0.0).then(|| Duration::from_secs_f64(self.total.saturating_sub(self.done) as f64 / bytes_per_sec));
+        (self.cb)(ProgressEvent::Copying { file: self.inputs[file], file_done, file_total, done: self.done, total: self.total, bytes_per_sec, eta });
+    }
+}
+
+/// Reader of the media data of a merged `file`, which reports the progress and fails when cancelled
+pub(crate) struct MediaRead<'a, 'b, R> {
+    pub inner: R,
+    pub file: usize,
+    pub progress: &'a mut Progress<'b>,
+    pub cancel: Option<&'a CancellationToken>,
+}
+
+impl<R: Read> Read for MediaRead<'_, '_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        cancel::check(self.cancel)?;
+        let read = self.inner.read(buf)?;
+        self.progress.copied(self.file, read as u64);
+        Ok(read)
+    }
+}
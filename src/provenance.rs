@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Provenance is stored in moov as a `uuid` box:
+//   uuid[16], version u8 (0), flags u24, timescale u32, source_count u32, then for each source:
+//   name_length u16, name (UTF-8), file_size u64, creation_time u64, data_offset u64, data_size u64,
+//   start_time u64, duration u64, track_count u32, then for each track: first_sample u32, sample_count u32
+// All values are big-endian. Times are in the movie timescale, except `creation_time` which is copied from
+// the source `mvhd` (seconds since 1904). `data_offset` is relative to the start of the merged `mdat` payload.
+
+use std::io::{ Read, Seek, SeekFrom, Result };
+use byteorder::{ BigEndian, ReadBytesExt, WriteBytesExt };
+use crate::{ fourcc, invalid_data, walk_boxes, InputReport, desc_reader::Desc };
+
+pub const PROVENANCE_UUID: [u8; 16] = [0x3f, 0x0c, 0x5a, 0x2e, 0x9b, 0x7d, 0x4c, 0x1e, 0x8a, 0x55, 0x1d, 0x2b, 0x6e, 0x7f, 0x9a, 0x04];
+
+/// Description of the source files of a merged file, stored in its `moov`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Provenance {
+    /// Movie timescale of `start_time` and `duration`
+    pub timescale: u32,
+    pub sources: Vec<SourceInfo>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceInfo {
+    /// File name, without the directory
+    pub name: String,
+    pub size: u64,
+    /// Creation time from `mvhd`, in seconds since 1904-01-01
+    pub creation_time: u64,
+    /// Range of the media data of this source, relative to the start of the `mdat` payload
+    pub data_offset: u64,
+    pub data_size: u64,
+    /// Start in the merged file, in movie timescale units
+    pub start_time: u64,
+    pub duration: u64,
+    /// First sample and sample count of each track
+    pub tracks: Vec<(u32, u32)>,
+}
+
+impl Provenance {
+    /// Serializes the whole `uuid` box
+    pub fn to_box(&self) -> Vec<u8> {
+        let mut b = Vec::new();
+        b.extend_from_slice(&[0; 4]);
+        b.extend_from_slice(&fourcc("uuid").to_be_bytes());
+        b.extend_from_slice(&PROVENANCE_UUID);
+        b.extend_from_slice(&[0; 4]); // version and flags
+        b.extend_from_slice(&self.timescale.to_be_bytes());
+        b.extend_from_slice(&(self.sources.len() as u32).to_be_bytes());
+        for x in &self.sources {
+            let name = &x.name.as_bytes()[..x.name.len().min(u16::MAX as usize)];
+            let _ = b.write_u16::<BigEndian>(name.len() as u16);
+            b.extend_from_slice(name);
+            for v in [x.size, x.creation_time, x.data_offset, x.data_size, x.start_time, x.duration] {
+                let _ = b.write_u64::<BigEndian>(v);
+            }
+            let _ = b.write_u32::<BigEndian>(x.tracks.len() as u32);
+            for (first_sample, sample_count) in &x.tracks {
+                let _ = b.write_u32::<BigEndian>(*first_sample);
+                let _ = b.write_u32::<BigEndian>(*sample_count);
+            }
+        }
+        let size = b.len() as u32;
+        b[..4].copy_from_slice(&size.to_be_bytes());
+        b
+    }
+
+    /// Parses the payload of the `uuid` box, after the uuid itself
+    pub fn parse(mut d: &[u8]) -> Result<Self> {
+        let too_short = || invalid_data("Provenance box is too short");
+        if d.read_u8()? != 0 {
+            return Err(invalid_data("Unsupported provenance version"));
+        }
+        d.read_u24::<BigEndian>()?;
+        let timescale = d.read_u32::<BigEndian>()?;
+        let count = d.read_u32::<BigEndian>()?;
+        let mut sources = Vec::new();
+        for _ in 0..count {
+            let name_len = d.read_u16::<BigEndian>()? as usize;
+            let name = String::from_utf8_lossy(d.get(..name_len).ok_or_else(too_short)?).into_owned();
+            d = &d[name_len..];
+            let mut v = [0u64; 6];
+            for x in &mut v { *x = d.read_u64::<BigEndian>()?; }
+            let track_count = d.read_u32::<BigEndian>()? as usize;
+            if d.len() < track_count * 8 { return Err(too_short()); }
+            let mut tracks = Vec::with_capacity(track_count);
+            for _ in 0..track_count {
+                tracks.push((d.read_u32::<BigEndian>()?, d.read_u32::<BigEndian>()?));
+            }
+            sources.push(SourceInfo { name, size: v[0], creation_time: v[1], data_offset: v[2], data_size: v[3], start_time: v[4], duration: v[5], tracks });
+        }
+        Ok(Self { timescale, sources })
+    }
+}
+
+/// Describes the merged inputs. `size` returns the file size of an input
+pub(crate) fn sources(inputs: &[InputReport], desc: &Desc, size: impl Fn(usize) -> u64, names: &[String]) -> Vec<SourceInfo> {
+    inputs.iter().enumerate().map(|(i, x)| SourceInfo {
+        name: names.get(x.index).cloned().unwrap_or_default(),
+        size: size(x.index),
+        creation_time: desc.mvhd_creation_time_per_file.get(i).copied().unwrap_or(0),
+        data_offset: x.data_offset,
+        data_size: x.data_size,
+        start_time: x.start_time,
+        duration: x.duration,
+        tracks: x.tracks.iter().map(|t| (t.first_sample, t.sample_count)).collect(),
+    }).collect()
+}
+
+/// Returns whether the box payload at the current position starts with the provenance uuid. The position is left unchanged.
+pub(crate) fn is_provenance<R: Read + Seek>(d: &mut R, payload_size: u64) -> Result<bool> {
+    if payload_size < 16 { return Ok(false); }
+    let mut uuid = [0u8; 16];
+    d.read_exact(&mut uuid)?;
+    d.seek(SeekFrom::Current(-16))?;
+    Ok(uuid == PROVENANCE_UUID)
+}
+
+/// Reads the provenance box of a file merged with [`MergeOptions::provenance`](crate::MergeOptions::provenance)
+pub fn read_provenance<R: Read + Seek>(reader: &mut R) -> Result<Option<Provenance>> {
+    let mut candidates = Vec::new();
+    reader.seek(SeekFrom::Start(0))?;
+    for x in walk_boxes(reader)? {
+        // Stop at trailing data which isn't a box, e.g. the Insta360 metadata
+        let Ok(x) = x else { break; };
+        if x.path == "moov/uuid" {
+            candidates.push(x.header);
+        }
+    }
+    for header in candidates {
+        reader.seek(SeekFrom::Start(header.payload_offset()))?;
+        if is_provenance(reader, header.payload_size())? {
+            let mut payload = vec![0u8; header.payload_size() as usize];
+            reader.read_exact(&mut payload)?;
+            return Provenance::parse(&payload[16..]).map(Some);
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ Read, Seek, SeekFrom, Result };
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// Default of [`MergeOptions::max_open_files`](crate::MergeOptions::max_open_files)
+pub(crate) const MAX_OPEN_FILES: usize = 64;
+
+/// Opens the inputs of a merge when they are read, so that thousands of chapters can be merged without running out of
+/// file descriptors. See [`Merger::input_provider`](crate::Merger::input_provider) and [`MergeOptions::max_open_files`](crate::MergeOptions::max_open_files),
+/// which limits the open inputs of all providers and paths of a merge together
+pub trait InputProvider {
+    type Input: Read + Seek;
+    /// Number of inputs
+    fn count(&self) -> usize;
+    /// Size of input `index` in bytes, without opening it
+    fn size(&mut self, index: usize) -> Result<u64>;
+    /// Opens input `index`. It can be opened again after it was dropped
+    fn open(&mut self, index: usize) -> Result<Self::Input>;
+}
+
+/// Inputs from paths
+pub(crate) struct Paths(pub Vec<PathBuf>);
+
+impl InputProvider for Paths {
+    type Input = File;
+    fn count(&self) -> usize { self.0.len() }
+    fn size(&mut self, index: usize) -> Result<u64> { Ok(std::fs::metadata(&self.0[index])?.len()) }
+    fn open(&mut self, index: usize) -> Result<File> { File::open(&self.0[index]) }
+}
+
+pub(crate) trait ReadSeek: Read + Seek { }
+impl<T: Read + Seek> ReadSeek for T { }
+type Open<'a> = Box<dyn FnMut() -> Result<Box<dyn ReadSeek + 'a>> + 'a>;
+
+/// Inputs of all providers of a merge which are open, the least recently used is closed first
+pub(crate) struct Pool<'a> {
+    inputs: Vec<Open<'a>>,
+    max_open: usize,
+    open: VecDeque<(usize, Box<dyn ReadSeek + 'a>, u64)>, // index in `inputs`, input, its position
+}
+
+impl<'a> Pool<'a> {
+    pub fn new(max_open: usize) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self { inputs: Vec::new(), max_open: max_open.max(1), open: VecDeque::new() }))
+    }
+
+    /// Adds the inputs of `provider`, returns their streams with their sizes
+    pub fn add<P: InputProvider + 'a>(pool: &Rc<RefCell<Self>>, provider: P) -> Result<Vec<(LazyInput<'a>, usize)>> {
+        let provider = Rc::new(RefCell::new(provider));
+        let count = provider.borrow().count();
+        (0..count).map(|i| {
+            let size = provider.borrow_mut().size(i)?;
+            let provider = provider.clone();
+            let mut inputs = RefCell::borrow_mut(pool);
+            inputs.inputs.push(Box::new(move || Ok(Box::new(provider.borrow_mut().open(i)?))));
+            Ok((LazyInput { pool: pool.clone(), index: inputs.inputs.len() - 1, pos: 0, size }, size as usize))
+        }).collect()
+    }
+
+    /// Keeps one of the open inputs for a file opened outside of the pool, see [`FileHandles`](crate::kernel_copy::FileHandles).
+    /// Returns false if the limit is 1
+    pub fn reserve(&mut self) -> bool {
+        if self.max_open < 2 { return false; }
+        self.max_open -= 1;
+        true
+    }
+
+    fn get(&mut self, index: usize) -> Result<&mut (usize, Box<dyn ReadSeek + 'a>, u64)> {
+        if let Some(i) = self.open.iter().position(|x| x.0 == index) {
+            let x = self.open.remove(i).unwrap();
+            self.open.push_back(x);
+        } else {
+            if self.open.len() >= self.max_open {
+                self.open.pop_front();
+            }
+            let input = (self.inputs[index])()?;
+            self.open.push_back((index, input, 0));
+        }
+        Ok(self.open.back_mut().unwrap())
+    }
+}
+
+/// Stream of an input which is opened by the pool when it's read
+pub(crate) struct LazyInput<'a> {
+    pool: Rc<RefCell<Pool<'a>>>,
+    index: usize,
+    pos: u64,
+    size: u64,
+}
+
+impl Read for LazyInput<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut pool = self.pool.borrow_mut();
+        let (_, input, pos) = pool.get(self.index)?;
+        if *pos != self.pos {
+            *pos = input.seek(SeekFrom::Start(self.pos))?;
+        }
+        let read = input.read(buf)?;
+        *pos += read as u64;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for LazyInput<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+            SeekFrom::End(x) => self.size.checked_add_signed(x),
+        };
+        self.pos = pos.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative position"))?;
+        Ok(self.pos)
+    }
+}
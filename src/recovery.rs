@@ -0,0 +1,633 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Recovery of chapters which have media data but no `moov` box, e.g. the last chapter of a recording when the camera lost power.
+// A healthy chapter from the same recording is used as a template: its sample entries, timing and chunk layout tell us
+// how the orphaned `mdat` is organized, and its `moov` is rewritten with sample tables rebuilt from scanning the media data.
+
+use std::io::{ Read, Seek, SeekFrom, Result, Cursor };
+use crate::{ fourcc, read_box, invalid_data, writer, progress::Progress, desc_reader::{ self, Desc, TrackDesc } };
+
+// How many samples of each template track are read to find the common sample signature
+const SIGNATURE_SAMPLES: usize = 500;
+// Number of constant bits needed to recognize the start of a chunk, and to split a chunk into samples
+const DETECTABLE_BITS: u32 = 16;
+const SPLITTABLE_BITS: u32 = 6;
+
+/// A chapter with rebuilt sample tables. Reads as a complete MP4 file: the original bytes up to the end of the media data,
+/// with the `mdat` size fixed, followed by the new `moov`.
+pub struct RecoveredStream<T: Read + Seek> {
+    inner: T,
+    inner_pos: Option<u64>,
+    data_end: u64,
+    header_patch: (u64, Vec<u8>),
+    moov: Vec<u8>,
+    pos: u64,
+}
+
+impl<T: Read + Seek> RecoveredStream<T> {
+    /// Wraps a healthy file without any changes, so it can be merged together with recovered chapters
+    pub fn passthrough(mut inner: T) -> Result<Self> {
+        let data_end = inner.seek(SeekFrom::End(0))?;
+        Ok(Self { inner, inner_pos: None, data_end, header_patch: (0, Vec::new()), moov: Vec::new(), pos: 0 })
+    }
+    pub fn len(&self) -> u64 { self.data_end + self.moov.len() as u64 }
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+impl<T: Read + Seek> Read for RecoveredStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos < self.data_end {
+            let len = buf.len().min((self.data_end - self.pos) as usize);
+            if self.inner_pos != Some(self.pos) {
+                self.inner.seek(SeekFrom::Start(self.pos))?;
+            }
+            let read = self.inner.read(&mut buf[..len])?;
+            self.inner_pos = Some(self.pos + read as u64);
+
+            // Apply the fixed mdat header
+            let (patch_pos, patch) = &self.header_patch;
+            for (i, b) in patch.iter().enumerate() {
+                let at = patch_pos + i as u64;
+                if at >= self.pos && at < self.pos + read as u64 {
+                    buf[(at - self.pos) as usize] = *b;
+                }
+            }
+            self.pos += read as u64;
+            Ok(read)
+        } else {
+            let offs = ((self.pos - self.data_end) as usize).min(self.moov.len());
+            let read = (&self.moov[offs..]).read(buf)?;
+            self.pos += read as u64;
+            Ok(read)
+        }
+    }
+}
+
+impl<T: Read + Seek> Seek for RecoveredStream<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(x)   => Some(x),
+            SeekFrom::End(x)     => self.len().checked_add_signed(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        self.pos = new_pos.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))?;
+        Ok(self.pos)
+    }
+}
+
+/// Finds a top-level box, returns its offset, size and header size
+pub(crate) fn find_box<R: Read + Seek>(stream: &mut R, typ: u32) -> Result<Option<(u64, u64, i64)>> {
+    stream.seek(SeekFrom::Start(0))?;
+    while let Ok((box_typ, offs, size, header_size)) = read_box(stream) {
+        if box_typ == typ {
+            return Ok(Some((offs, size, header_size)));
+        }
+        stream.seek(SeekFrom::Start(offs.saturating_add(size)))?;
+    }
+    Ok(None)
+}
+
+/// Returns `true` if the file has a `moov` box, i.e. it doesn't need recovery
+pub fn has_moov<R: Read + Seek>(stream: &mut R) -> Result<bool> {
+    Ok(find_box(stream, fourcc("moov"))?.is_some())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum SampleKind {
+    Nal { hevc: bool, length_size: u8 },
+    Fixed(u32),
+    Variable,
+}
+
+struct TrackModel {
+    kind: SampleKind,
+    signature: Vec<(u8, u8)>, // Mask and value of the bits which are the same at the start of all template samples
+    min_size: u32,
+    max_size: u32,
+    avg_size: f64,
+    delta: u32, // Most common sample duration
+}
+impl TrackModel {
+    // Whether the start of this track's chunk can be reliably recognized in the media data
+    fn is_detectable(&self) -> bool {
+        matches!(self.kind, SampleKind::Nal { .. }) || self.signature_bits() >= DETECTABLE_BITS
+    }
+    fn signature_bits(&self) -> u32 {
+        self.signature.iter().map(|x| x.0.count_ones()).sum()
+    }
+    fn matches_signature(&self, data: &[u8]) -> bool {
+        data.len() >= self.signature.len() && self.signature.iter().zip(data).all(|((mask, value), x)| x & mask == *value)
+    }
+}
+
+// Chunks of the template in file order: track index and number of samples
+type ChunkSequence = Vec<(usize, u32)>;
+
+#[derive(Default)]
+struct RecoveredTrack {
+    sizes: Vec<u32>,
+    sync: Vec<u32>,
+    chunks: Vec<(u64, u32)>, // offset relative to the mdat data, number of samples
+}
+
+struct Scanner<'a, R: Read + Seek> {
+    stream: &'a mut R,
+    end: u64,
+    models: &'a [TrackModel],
+    cache: Vec<u8>,
+    cache_pos: u64,
+}
+
+impl<R: Read + Seek> Scanner<'_, R> {
+    fn read_at(&mut self, pos: u64, len: usize) -> Result<&[u8]> {
+        let len = len.min(self.end.saturating_sub(pos) as usize);
+        if pos < self.cache_pos || pos + len as u64 > self.cache_pos + self.cache.len() as u64 {
+            self.cache.resize(len.max(64*1024).min(self.end.saturating_sub(pos) as usize), 0);
+            self.cache_pos = pos;
+            self.stream.seek(SeekFrom::Start(pos))?;
+            self.stream.read_exact(&mut self.cache)?;
+        }
+        let offs = (pos - self.cache_pos) as usize;
+        Ok(&self.cache[offs..offs + len])
+    }
+
+    // Parses a single access unit made of length-prefixed NAL units, returns its size and whether it's a sync sample
+    fn parse_access_unit(&mut self, track: usize, start: u64) -> Result<Option<(u64, bool)>> {
+        let (hevc, length_size) = match self.models[track].kind { SampleKind::Nal { hevc, length_size } => (hevc, length_size as usize), _ => return Ok(None) };
+        let max_nal_size = self.models[track].max_size as u64 * 2;
+        let end = self.end;
+        let mut pos = start;
+        let mut seen_vcl = false;
+        let mut sync = false;
+        loop {
+            let header = self.read_at(pos, length_size + 3)?;
+            if header.len() < length_size + 3 {
+                break;
+            }
+            let nal_size = header[..length_size].iter().fold(0u64, |acc, x| (acc << 8) | *x as u64);
+            let nal = &header[length_size..];
+            let valid_size = nal_size >= if hevc { 3 } else { 2 } && nal_size <= max_nal_size && pos + length_size as u64 + nal_size <= end;
+            let info = if hevc { hevc_nal_info(nal) } else { h264_nal_info(nal) };
+            let Some((is_vcl, starts_au, is_sync)) = info.filter(|_| valid_size) else { break; };
+            if pos == start && !starts_au {
+                return Ok(None);
+            }
+            if seen_vcl && starts_au {
+                break;
+            }
+            seen_vcl |= is_vcl;
+            sync |= is_sync;
+            pos += length_size as u64 + nal_size;
+        }
+        Ok(if seen_vcl { Some((pos - start, sync)) } else { None })
+    }
+
+    fn can_start(&mut self, track: usize, pos: u64) -> Result<bool> {
+        let model = &self.models[track];
+        match model.kind {
+            SampleKind::Nal { .. } => Ok(self.parse_access_unit(track, pos)?.is_some()),
+            _ if model.is_detectable() => {
+                let models = self.models;
+                Ok(models[track].matches_signature(self.read_at(pos, models[track].signature.len())?))
+            },
+            _ => Ok(false)
+        }
+    }
+
+    fn detect(&mut self, expected: usize, pos: u64) -> Result<Option<usize>> {
+        if self.can_start(expected, pos)? {
+            return Ok(Some(expected));
+        }
+        for track in 0..self.models.len() {
+            if track != expected && self.can_start(track, pos)? {
+                return Ok(Some(track));
+            }
+        }
+        Ok(None)
+    }
+
+    // Returns the sizes of samples in a chunk of `track` starting at `pos`, and their sync flags
+    fn parse_chunk(&mut self, track: usize, pos: u64, samples: u32) -> Result<Option<Vec<(u32, bool)>>> {
+        let model = &self.models[track];
+        match model.kind {
+            SampleKind::Nal { .. } => {
+                let mut ret = Vec::new();
+                let mut pos = pos;
+                while ret.len() < samples.max(1) as usize {
+                    match self.parse_access_unit(track, pos)? {
+                        Some((size, sync)) => { ret.push((size as u32, sync)); pos += size; },
+                        None => break
+                    }
+                }
+                Ok(if ret.is_empty() { None } else { Some(ret) })
+            },
+            SampleKind::Fixed(size) => {
+                if pos + size as u64 * samples as u64 > self.end {
+                    return Ok(None);
+                }
+                Ok(Some(vec![(size, true); samples as usize]))
+            },
+            SampleKind::Variable => {
+                // The chunk ends where a chunk of another track starts
+                let from = pos + (model.min_size as u64 * samples as u64 / 2).max(1);
+                let to = (pos + model.max_size as u64 * samples as u64 * 2 + 16).min(self.end);
+                let mut chunk_end = None;
+                'search: for p in from..to {
+                    for t in 0..self.models.len() {
+                        if t != track && self.can_start(t, p)? {
+                            chunk_end = Some(p);
+                            break 'search;
+                        }
+                    }
+                }
+                let Some(chunk_end) = chunk_end else {
+                    // There's no next chunk, so this is the end of the data, possibly cut off in the middle of a sample
+                    if self.end - pos > model.max_size as u64 * samples as u64 * 2 + 16 {
+                        return Ok(None);
+                    }
+                    let data = self.read_at(pos, (self.end - pos) as usize)?.to_vec();
+                    return Ok(split_tail(&data, &self.models[track]).map(|x| x.into_iter().map(|x| (x, true)).collect()));
+                };
+                let data = self.read_at(pos, (chunk_end - pos) as usize)?.to_vec();
+                Ok(Some(split_samples(&data, &self.models[track], samples).into_iter().map(|x| (x, true)).collect()))
+            }
+        }
+    }
+}
+
+fn h264_nal_info(nal: &[u8]) -> Option<(bool, bool, bool)> { // is_vcl, starts_access_unit, is_sync
+    if nal[0] & 0x80 != 0 { return None; }
+    let typ = nal[0] & 0x1f;
+    if typ == 0 || typ > 21 { return None; }
+    let is_vcl = typ == 1 || typ == 5;
+    let first_slice = is_vcl && nal[1] & 0x80 != 0; // first_mb_in_slice == 0
+    Some((is_vcl, first_slice || matches!(typ, 6..=9 | 14..=18), typ == 5))
+}
+
+fn hevc_nal_info(nal: &[u8]) -> Option<(bool, bool, bool)> { // is_vcl, starts_access_unit, is_sync
+    if nal[0] & 0x80 != 0 || nal[1] & 0x07 == 0 { return None; }
+    let typ = (nal[0] >> 1) & 0x3f;
+    if matches!(typ, 10..=15 | 22..=31) || typ > 40 { return None; }
+    let is_vcl = typ < 32;
+    let first_slice = is_vcl && nal[2] & 0x80 != 0; // first_slice_segment_in_pic_flag
+    Some((is_vcl, first_slice || matches!(typ, 32..=35 | 39), (16..=21).contains(&typ)))
+}
+
+// Splits a chunk of variable sized samples on positions matching the sample signature,
+// choosing the boundaries which keep the sizes closest to the template's average.
+fn split_samples(data: &[u8], model: &TrackModel, samples: u32) -> Vec<u32> {
+    let n = samples.max(1) as usize;
+    if n == 1 {
+        return vec![data.len() as u32];
+    }
+    if let Some((_, sizes)) = segment(data, model, n) {
+        return sizes;
+    }
+    log::warn!("Couldn't find sample boundaries, splitting the chunk evenly");
+    let size = data.len() / n;
+    let mut ret = vec![size as u32; n];
+    ret[n - 1] = (data.len() - size * (n - 1)) as u32;
+    ret
+}
+
+// Splits the data at the end of the mdat, where the number of samples is unknown.
+// Returns `None` if it doesn't look like complete samples.
+fn split_tail(data: &[u8], model: &TrackModel) -> Option<Vec<u32>> {
+    let min_samples = (data.len() / (model.max_size as usize * 3 / 2).max(1)).max(1);
+    let max_samples = (data.len() / (model.min_size as usize / 2).max(1)).min(min_samples + 64);
+    (min_samples..=max_samples)
+        .filter_map(|n| segment(data, model, n).map(|(cost, sizes)| (cost / n as f64, sizes)))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|x| x.1)
+}
+
+// Returns the lowest cost segmentation of `data` into `n` samples, or `None` if there's no valid one
+fn segment(data: &[u8], model: &TrackModel, n: usize) -> Option<(f64, Vec<u32>)> {
+    if n == 1 && !data.is_empty() {
+        return Some(((data.len() as f64 - model.avg_size).powi(2), vec![data.len() as u32]));
+    }
+    if model.signature_bits() < SPLITTABLE_BITS || !model.matches_signature(data) {
+        return None;
+    }
+    let candidates: Vec<usize> = (0..data.len()).filter(|i| model.matches_signature(&data[*i..])).collect();
+    let min = (model.min_size / 2) as usize;
+    let max = (model.max_size as usize * 3 / 2).max(1);
+    let cost = |size: usize| (size as f64 - model.avg_size).powi(2);
+
+    // best[j][c] - lowest cost of placing sample j at candidates[c]
+    let mut best = vec![vec![f64::INFINITY; candidates.len()]; n];
+    let mut prev = vec![vec![usize::MAX; candidates.len()]; n];
+    best[0][0] = 0.0;
+    for j in 1..n {
+        for c in 0..candidates.len() {
+            if best[j - 1][c].is_infinite() { continue; }
+            for (nc, pos) in candidates.iter().enumerate().skip(c + 1) {
+                let size = pos - candidates[c];
+                if size > max { break; }
+                if size < min { continue; }
+                let new_cost = best[j - 1][c] + cost(size);
+                if new_cost < best[j][nc] {
+                    best[j][nc] = new_cost;
+                    prev[j][nc] = c;
+                }
+            }
+        }
+    }
+    let total_cost = |c: usize| best[n - 1][c] + cost(data.len() - candidates[c]);
+    let mut c = (0..candidates.len())
+        .filter(|c| !best[n - 1][*c].is_infinite() && (min..=max).contains(&(data.len() - candidates[*c])))
+        .min_by(|a, b| total_cost(*a).total_cmp(&total_cost(*b)))?;
+    let cost = total_cost(c);
+    let mut starts = vec![0; n];
+    for j in (0..n).rev() {
+        starts[j] = candidates[c];
+        c = prev[j][c];
+    }
+    Some((cost, (0..n).map(|j| (starts.get(j + 1).copied().unwrap_or(data.len()) - starts[j]) as u32).collect()))
+}
+
+fn build_models<R: Read + Seek>(reference: &mut R, desc: &Desc) -> Result<(Vec<TrackModel>, ChunkSequence)> {
+    let mdat_data = desc.mdat_position.first().ok_or_else(|| invalid_data("Reference has no mdat"))?.1;
+    let mut models = Vec::with_capacity(desc.moov_tracks.len());
+    let mut sequence = Vec::new();
+    for (track, t) in desc.moov_tracks.iter().enumerate() {
+        let samples_per_chunk = t.samples_per_chunk();
+        let mut signature: Option<Vec<(u8, u8)>> = None;
+        let mut sample = 0;
+        for (offset, samples) in t.stco.iter().zip(&samples_per_chunk) {
+            sequence.push((mdat_data + offset, track, *samples));
+            let mut pos = mdat_data + offset;
+            for _ in 0..*samples {
+                if sample < SIGNATURE_SAMPLES && t.stsz_sample_size == 0 {
+                    let mut buf = vec![0u8; t.sample_size(sample).min(4) as usize];
+                    reference.seek(SeekFrom::Start(pos))?;
+                    reference.read_exact(&mut buf)?;
+                    let common = signature.get_or_insert_with(|| buf.iter().map(|x| (0xff, *x)).collect());
+                    common.truncate(buf.len());
+                    for ((mask, value), x) in common.iter_mut().zip(&buf) {
+                        *mask &= !(*value ^ x);
+                        *value &= *mask;
+                    }
+                }
+                pos += t.sample_size(sample) as u64;
+                sample += 1;
+            }
+        }
+        let sizes = (0..t.stsz_count as usize).map(|i| t.sample_size(i));
+        let (min_size, max_size, sum) = sizes.fold((u32::MAX, 0, 0u64), |(min, max, sum), x| (min.min(x), max.max(x), sum + x as u64));
+        let mut deltas = std::collections::BTreeMap::<u32, u64>::new();
+        for (count, delta) in &t.stts { *deltas.entry(*delta).or_default() += *count as u64; }
+
+        let kind = if t.nal_length_size > 0 {
+            SampleKind::Nal { hevc: t.codec == fourcc("hvc1") || t.codec == fourcc("hev1"), length_size: t.nal_length_size }
+        } else if t.stsz_sample_size != 0 {
+            SampleKind::Fixed(t.stsz_sample_size)
+        } else {
+            SampleKind::Variable
+        };
+        models.push(TrackModel {
+            kind,
+            signature: signature.unwrap_or_default(),
+            min_size: if t.stsz_count > 0 { min_size } else { 0 },
+            max_size,
+            avg_size: if t.stsz_count > 0 { sum as f64 / t.stsz_count as f64 } else { 0.0 },
+            delta: deltas.iter().max_by_key(|x| x.1).map(|x| *x.0).unwrap_or(1),
+        });
+    }
+    sequence.sort_by_key(|x| x.0);
+    Ok((models, sequence.into_iter().map(|x| (x.1, x.2)).collect()))
+}
+
+/// Rebuilds the sample tables of `truncated`, which has an `mdat` but no `moov`, using `reference` - a healthy chapter
+/// recorded by the same camera with the same settings - as the template.
+pub fn recover_stream<R: Read + Seek, T: Read + Seek>(reference: &mut R, mut truncated: T) -> Result<RecoveredStream<T>> {
+    let reference_size = reference.seek(SeekFrom::End(0))?;
+    reference.seek(SeekFrom::Start(0))?;
+    let mut reference_desc = Desc::default();
+    desc_reader::read_file_desc(&mut std::io::BufReader::with_capacity(16*1024, &mut *reference), reference_size as usize, &mut reference_desc, 0)?;
+    if reference_desc.moov_tracks.is_empty() {
+        return Err(invalid_data("Reference file has no tracks"));
+    }
+    let (models, sequence) = build_models(reference, &reference_desc)?;
+    if sequence.is_empty() {
+        return Err(invalid_data("Reference file has no samples"));
+    }
+    if !models.iter().any(|x| x.is_detectable()) {
+        return Err(invalid_data("Reference file has no track with recognizable samples"));
+    }
+    if reference_desc.moov_tracks.iter().zip(&models).any(|(t, m)| matches!(m.kind, SampleKind::Nal { .. }) && t.stts.len() > 1) {
+        log::warn!("Video in the reference file has variable frame timing, recovered frames will use the most common frame duration");
+    }
+
+    let file_end = truncated.seek(SeekFrom::End(0))?;
+    let (mdat_offs, mdat_size, mdat_header_size) = find_box(&mut truncated, fourcc("mdat"))?.ok_or_else(|| invalid_data("Truncated file has no mdat"))?;
+    let data_start = mdat_offs + mdat_header_size as u64;
+    let data_end = if mdat_offs + mdat_size <= file_end && mdat_size > mdat_header_size as u64 { mdat_offs + mdat_size } else { file_end };
+
+    let mut tracks: Vec<RecoveredTrack> = (0..models.len()).map(|_| Default::default()).collect();
+    {
+        let mut scanner = Scanner { stream: &mut truncated, end: data_end, models: &models, cache: Vec::new(), cache_pos: 0 };
+        let steady_start = sequence.len() / 2;
+        let sequence_at = |k: usize| if k < sequence.len() { sequence[k] } else { sequence[steady_start + (k - sequence.len()) % (sequence.len() - steady_start)] };
+        let mut k = 0;
+        let mut pos = data_start;
+        while pos < data_end {
+            let (expected, _) = sequence_at(k);
+            let track = match scanner.detect(expected, pos)? {
+                Some(track) => {
+                    if track != expected {
+                        if let Some(i) = (k..k + sequence.len()).find(|i| sequence_at(*i).0 == track) { k = i; }
+                    }
+                    track
+                },
+                None if models[expected].is_detectable() => break,
+                None => expected
+            };
+            let samples = sequence_at(k).1;
+            let Some(chunk) = scanner.parse_chunk(track, pos, samples)? else { break; };
+
+            let t = &mut tracks[track];
+            t.chunks.push((pos - data_start, chunk.len() as u32));
+            for (size, sync) in chunk {
+                t.sizes.push(size);
+                if sync { t.sync.push(t.sizes.len() as u32); }
+                pos += size as u64;
+            }
+            k += 1;
+        }
+        log::debug!("Recovered {} of {} bytes of media data", pos - data_start, data_end - data_start);
+    }
+
+    // Build the description of the recovered chapter
+    let mut desc = Desc {
+        moov_mvhd_timescale: reference_desc.moov_mvhd_timescale,
+        mdat_final_position: data_start,
+        ..Default::default()
+    };
+    for ((reference_track, model), t) in reference_desc.moov_tracks.iter().zip(&models).zip(&tracks) {
+        let count = t.sizes.len() as u32;
+        let mdhd_duration = count as u64 * model.delta as u64;
+        let tkhd_duration = desc_reader::rescale(mdhd_duration, reference_track.mdhd_timescale, desc.moov_mvhd_timescale);
+        let mut stsc: Vec<(u32, u32, u32)> = Vec::new();
+        for (i, (_, samples)) in t.chunks.iter().enumerate() {
+            if stsc.last().map(|x| x.1) != Some(*samples) {
+                stsc.push((i as u32 + 1, *samples, 1));
+            }
+        }
+        desc.moov_mvhd_duration = desc.moov_mvhd_duration.max(tkhd_duration);
+        desc.moov_tracks.push(TrackDesc {
+            tkhd_duration,
+            elst_segment_duration: if reference_track.elst_segment_duration != 0 { tkhd_duration } else { 0 },
+            mdhd_timescale: reference_track.mdhd_timescale,
+            mdhd_duration,
+            stts: if count > 0 { vec![(count, model.delta)] } else { Vec::new() },
+            stsz: if let SampleKind::Fixed(_) = model.kind { Vec::new() } else { t.sizes.clone() },
+            stco: t.chunks.iter().map(|x| x.0).collect(),
+            stss: if matches!(model.kind, SampleKind::Nal { .. }) { t.sync.clone() } else { Vec::new() },
+            stsz_sample_size: if let SampleKind::Fixed(size) = model.kind { size } else { 0 },
+            stsz_count: count,
+            stsc,
+            skip: reference_track.skip,
+            codec: reference_track.codec,
+            nal_length_size: reference_track.nal_length_size,
+            handler: reference_track.handler,
+            width: reference_track.width,
+            height: reference_track.height,
+            sample_rate: reference_track.sample_rate,
+            ..Default::default()
+        });
+        log::info!("Recovered {count} samples of track {}", crate::typ_to_str(reference_track.codec));
+    }
+
+    // Write the new moov using the reference as the template
+    let (moov_offs, moov_size, _) = find_box(reference, fourcc("moov"))?.ok_or_else(|| invalid_data("Reference file has no moov"))?;
+    reference.seek(SeekFrom::Start(moov_offs))?;
+    let mut moov = Cursor::new(Vec::new());
+    writer::rewrite_from_desc(&mut [(&mut *reference, reference_size as usize)], &mut moov, &mut desc, &mut Progress::new(&mut |_| { }), 0, moov_size)?;
+
+    let new_mdat_size = data_end - mdat_offs;
+    let header_patch = if mdat_header_size == 16 {
+        (mdat_offs + 8, new_mdat_size.to_be_bytes().to_vec())
+    } else if let Ok(size) = u32::try_from(new_mdat_size) {
+        (mdat_offs, size.to_be_bytes().to_vec())
+    } else {
+        return Err(invalid_data("mdat of the truncated file is too large for its 32-bit header"));
+    };
+
+    Ok(RecoveredStream { inner: truncated, inner_pos: None, data_end, header_patch, moov: moov.into_inner(), pos: 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn model(signature: &[u8], min_size: u32, max_size: u32, avg_size: f64) -> TrackModel {
+        TrackModel { kind: SampleKind::Variable, signature: signature.iter().map(|x| (0xff, *x)).collect(), min_size, max_size, avg_size, delta: 1 }
+    }
+
+    // Samples of the given sizes, each starting with `signature` followed by zeros
+    fn samples(signature: &[u8], sizes: &[usize]) -> Vec<u8> {
+        sizes.iter().flat_map(|size| {
+            let mut x = vec![0u8; *size];
+            x[..signature.len()].copy_from_slice(signature);
+            x
+        }).collect()
+    }
+
+    #[test]
+    fn segment_finds_boundaries() {
+        let m = model(&[0xaa, 0xbb], 4, 8, 6.0);
+        let data = samples(&[0xaa, 0xbb], &[6, 5, 7]);
+        let (cost, sizes) = segment(&data, &m, 3).unwrap();
+        assert_eq!(sizes, [6, 5, 7]);
+        assert_eq!(cost, 2.0);
+        // Not enough signatures for more samples
+        assert!(segment(&data, &m, 4).is_none());
+    }
+
+    #[test]
+    fn segment_rejects_data_without_signature() {
+        let m = model(&[0xaa, 0xbb], 4, 8, 6.0);
+        let mut data = samples(&[0xaa, 0xbb], &[6, 6]);
+        data[0] = 0;
+        assert!(segment(&data, &m, 2).is_none());
+        // Too few bits to tell the samples apart
+        let weak = TrackModel { signature: vec![(0x07, 0x05)], ..model(&[], 4, 8, 6.0) };
+        assert!(segment(&[0x05, 0, 0, 0, 0x05, 0, 0, 0], &weak, 2).is_none());
+    }
+
+    #[test]
+    fn segment_respects_size_limits() {
+        let m = model(&[0xaa, 0xbb], 6, 8, 6.0);
+        // The 2 byte sample is below half of the minimum size, so it's joined to one of its neighbours
+        let data = samples(&[0xaa, 0xbb], &[6, 2, 5]);
+        assert_eq!(segment(&data, &m, 2).unwrap().1, [6, 7]);
+        assert!(segment(&data, &m, 3).is_none());
+    }
+
+    #[test]
+    fn split_samples_falls_back_to_even_sizes() {
+        let m = model(&[0xaa, 0xbb], 4, 8, 6.0);
+        assert_eq!(split_samples(&[0u8; 14], &m, 3), [4, 4, 6]);
+        assert_eq!(split_samples(&samples(&[0xaa, 0xbb], &[6, 5, 7]), &m, 3), [6, 5, 7]);
+    }
+
+    #[test]
+    fn split_tail_finds_sample_count() {
+        let m = model(&[0xaa, 0xbb], 4, 8, 6.0);
+        assert_eq!(split_tail(&samples(&[0xaa, 0xbb], &[6, 5, 7, 6, 6, 5]), &m).unwrap(), [6, 5, 7, 6, 6, 5]);
+        // A single sample isn't free, the two short ones aren't merged
+        assert_eq!(split_tail(&samples(&[0xaa, 0xbb], &[6, 5]), &m).unwrap(), [6, 5]);
+        assert!(split_tail(&[0u8; 30], &m).is_none());
+    }
+
+    #[test]
+    fn nal_info() {
+        assert_eq!(h264_nal_info(&[0x65, 0x88, 0]), Some((true, true, true))); // IDR, first slice
+        assert_eq!(h264_nal_info(&[0x41, 0x00, 0]), Some((true, false, false))); // non-IDR, not the first slice
+        assert_eq!(h264_nal_info(&[0x06, 0x05, 0]), Some((false, true, false))); // SEI
+        assert_eq!(h264_nal_info(&[0x80, 0, 0]), None); // forbidden bit
+        assert_eq!(hevc_nal_info(&[0x26, 0x01, 0x80]), Some((true, true, true))); // IDR_W_RADL
+        assert_eq!(hevc_nal_info(&[0x02, 0x01, 0x00]), Some((true, false, false))); // TRAIL_R, not the first slice
+        assert_eq!(hevc_nal_info(&[0x40, 0x01, 0x0c]), Some((false, true, false))); // VPS
+        assert_eq!(hevc_nal_info(&[0x26, 0x00, 0x80]), None); // temporal id 0
+    }
+
+    // Length prefixed NAL units
+    fn nals(units: &[&[u8]]) -> Vec<u8> {
+        units.iter().flat_map(|x| (x.len() as u32).to_be_bytes().into_iter().chain(x.iter().copied())).collect()
+    }
+
+    #[test]
+    fn scanner_parses_access_units() {
+        let models = [TrackModel { kind: SampleKind::Nal { hevc: false, length_size: 4 }, ..model(&[], 4, 100, 10.0) }];
+        let data = nals(&[&[0x06, 0x05, 0x01], &[0x65, 0x88, 0x00, 0x00], &[0x41, 0x80, 0x00], &[0x41, 0x00, 0x00], &[0x41, 0x80, 0x00]]);
+        let mut stream = Cursor::new(data.clone());
+        let mut scanner = Scanner { stream: &mut stream, end: data.len() as u64, models: &models, cache: Vec::new(), cache_pos: 0 };
+        // SEI and IDR, then a frame of two slices, then a frame of one slice
+        assert_eq!(scanner.parse_access_unit(0, 0).unwrap(), Some((15, true)));
+        assert_eq!(scanner.parse_access_unit(0, 15).unwrap(), Some((14, false)));
+        assert_eq!(scanner.parse_chunk(0, 0, 5).unwrap(), Some(vec![(15, true), (14, false), (7, false)]));
+        // Not at the start of an access unit
+        assert_eq!(scanner.parse_access_unit(0, 22).unwrap(), None);
+        assert!(scanner.can_start(0, 0).unwrap());
+        assert!(!scanner.can_start(0, 1).unwrap());
+    }
+
+    #[test]
+    fn scanner_ends_chunks_at_other_tracks() {
+        let models = [model(&[0xaa, 0xbb], 4, 8, 6.0), model(&[0xcc, 0xdd], 4, 8, 6.0)];
+        let mut data = samples(&[0xaa, 0xbb], &[6, 5]);
+        data.extend(samples(&[0xcc, 0xdd], &[7, 6]));
+        let mut stream = Cursor::new(data.clone());
+        let mut scanner = Scanner { stream: &mut stream, end: data.len() as u64, models: &models, cache: Vec::new(), cache_pos: 0 };
+        assert_eq!(scanner.detect(0, 11).unwrap(), Some(1));
+        assert_eq!(scanner.detect(1, 0).unwrap(), Some(0));
+        assert_eq!(scanner.detect(0, 3).unwrap(), None);
+        assert_eq!(scanner.parse_chunk(0, 0, 2).unwrap(), Some(vec![(6, true), (5, true)]));
+        // The last chunk goes to the end of the data
+        assert_eq!(scanner.parse_chunk(1, 11, 2).unwrap(), Some(vec![(7, true), (6, true)]));
+    }
+}
@@ -0,0 +1,117 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Summary of a merge, returned by [`join_files`](crate::join_files)
+#[derive(Debug, Clone, Default)]
+pub struct MergeReport {
+    /// Where each merged input starts in the output, in output order
+    pub inputs: Vec<InputReport>,
+    /// Input files which were excluded from the output in tolerant mode
+    pub skipped: Vec<SkippedInput>,
+    /// Movie timescale (units per second) of the output
+    pub timescale: u32,
+    /// Total duration in `timescale` units
+    pub duration: u64,
+    /// Total size of the merged media data in bytes
+    pub media_data_size: u64,
+    pub output_size: u64,
+    /// Checksum of the merged media data, if [`MergeOptions::checksum`](crate::MergeOptions::checksum) was set
+    pub checksum: Option<Vec<u8>>,
+    /// Time spent reading the input descriptions
+    pub scan_time: Duration,
+    /// Time spent writing the output
+    pub write_time: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SkippedInput {
+    /// Index of the file in the input list
+    pub index: usize,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InputReport {
+    /// Index of the file in the input list
+    pub index: usize,
+    /// Start in the output, in movie timescale units
+    pub start_time: u64,
+    /// Duration in movie timescale units
+    pub duration: u64,
+    /// Offset of the media data of this input in the output file
+    pub data_offset: u64,
+    pub data_size: u64,
+    /// Checksum of the media data of this input, if [`MergeOptions::checksum`](crate::MergeOptions::checksum) was set
+    pub checksum: Option<Vec<u8>>,
+    pub tracks: Vec<TrackBoundary>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TrackBoundary {
+    /// Index of the first sample of this input in the merged track
+    pub first_sample: u32,
+    pub sample_count: u32,
+    /// Start in the output, in track timescale units
+    pub start_time: u64,
+    /// Track timescale (units per second)
+    pub timescale: u32,
+}
+
+impl MergeReport {
+    pub fn duration_secs(&self) -> f64 { secs(self.duration, self.timescale) }
+
+    /// Serializes the report as JSON
+    pub fn to_json(&self) -> String {
+        let mut s = String::new();
+        s.push_str("{\n  \"inputs\": [");
+        for (i, x) in self.inputs.iter().enumerate() {
+            if i > 0 { s.push(','); }
+            let _ = write!(s, "\n    {{ \"index\": {}, \"start_time\": {}, \"duration\": {}, \"data_offset\": {}, \"data_size\": {}, {}\"tracks\": [",
+                x.index, secs(x.start_time, self.timescale), secs(x.duration, self.timescale), x.data_offset, x.data_size, checksum_field(&x.checksum, " "));
+            for (j, t) in x.tracks.iter().enumerate() {
+                if j > 0 { s.push(','); }
+                let _ = write!(s, "\n      {{ \"first_sample\": {}, \"sample_count\": {}, \"start_time\": {}, \"start_time_units\": {}, \"timescale\": {} }}",
+                    t.first_sample, t.sample_count, secs(t.start_time, t.timescale), t.start_time, t.timescale);
+            }
+            s.push_str(if x.tracks.is_empty() { "] }" } else { "\n    ] }" });
+        }
+        s.push_str(if self.inputs.is_empty() { "],\n" } else { "\n  ],\n" });
+        s.push_str("  \"skipped\": [");
+        for (i, x) in self.skipped.iter().enumerate() {
+            if i > 0 { s.push(','); }
+            let _ = write!(s, "\n    {{ \"index\": {}, \"reason\": {} }}", x.index, json_string(&x.reason));
+        }
+        s.push_str(if self.skipped.is_empty() { "],\n" } else { "\n  ],\n" });
+        let _ = write!(s, "  \"duration\": {},\n  \"media_data_size\": {},\n  \"output_size\": {},\n  {}\"scan_time\": {},\n  \"write_time\": {}\n}}\n",
+            self.duration_secs(), self.media_data_size, self.output_size, checksum_field(&self.checksum, "\n  "), self.scan_time.as_secs_f64(), self.write_time.as_secs_f64());
+        s
+    }
+}
+
+// `"checksum": "HEX",` followed by `separator`, or nothing
+fn checksum_field(checksum: &Option<Vec<u8>>, separator: &str) -> String {
+    checksum.as_ref().map(|x| format!("\"checksum\": \"{}\",{separator}", crate::to_hex(x))).unwrap_or_default()
+}
+
+fn secs(value: u64, timescale: u32) -> f64 {
+    if timescale == 0 { 0.0 } else { value as f64 / timescale as f64 }
+}
+
+fn json_string(s: &str) -> String {
+    let mut ret = String::with_capacity(s.len() + 2);
+    ret.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => ret.push_str("\\\""),
+            '\\' => ret.push_str("\\\\"),
+            '\n' => ret.push_str("\\n"),
+            c if (c as u32) < 0x20 => { let _ = write!(ret, "\\u{:04x}", c as u32); },
+            c => ret.push(c)
+        }
+    }
+    ret.push('"');
+    ret
+}
@@ -0,0 +1,285 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, Result };
+use std::ops::Range;
+use std::path::Path;
+use crate::{ scan_files, MergeOptions, desc_reader::{ self, Desc, TrackDesc } };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleInfo {
+    pub track: usize,
+    /// Index of the sample in the (merged) track, starting from 0
+    pub index: u32,
+    /// Decode timestamp in the track timescale
+    pub dts: u64,
+    /// Presentation timestamp (`dts` + composition offset) in the track timescale
+    pub pts: i64,
+    /// Duration in the track timescale
+    pub duration: u32,
+    /// Offset of the sample data in the merged media data (the `mdat` payload of the output file)
+    pub merged_offset: u64,
+    /// Index of the input file which contains the sample
+    pub source: usize,
+    /// Offset of the sample data in the input file
+    pub source_offset: u64,
+    pub size: u32,
+    pub sync: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Source {
+    input: usize,
+    file: usize, // index among the merged files, i.e. without the skipped ones
+    data_start: u64, // in the merged media data
+    data_size: u64,
+    file_offset: u64, // of the mdat payload in the input file
+}
+
+/// Sample tables of a file or of a planned merge of multiple files
+pub struct SampleTable {
+    pub(crate) desc: Desc,
+    sources: Vec<Source>,
+}
+
+/// Reads the sample tables of `files`. A single file describes that file, multiple files describe the merged output.
+pub fn sample_table<P: AsRef<Path>>(files: &[P]) -> Result<SampleTable> {
+    let mut open_files = Vec::with_capacity(files.len());
+    for x in files {
+        let f = std::fs::File::open(x)?;
+        let size = f.metadata()?.len() as usize;
+        open_files.push((f, size));
+    }
+    sample_table_file_streams(&mut open_files)
+}
+
+pub fn sample_table_file_streams<I: Read + Seek>(files: &mut [(I, usize)]) -> Result<SampleTable> {
+    SampleTable::from_scan(scan_files(files, &MergeOptions::default(), &mut |_| { })?)
+}
+
+impl SampleTable {
+    pub(crate) fn from_scan(scan: crate::Scan) -> Result<Self> {
+        let inputs = scan.usable.iter().enumerate().filter(|x| *x.1).map(|x| x.0).collect::<Vec<_>>();
+        let mut sources = Vec::with_capacity(scan.desc.mdat_position.len());
+        let mut data_start = 0;
+        for (file_index, file_offset, data_size) in &scan.desc.mdat_position {
+            if let Some((file, input)) = file_index.and_then(|x| Some((x, inputs.get(x)?))) {
+                sources.push(Source { input: *input, file, data_start, data_size: *data_size, file_offset: *file_offset });
+                data_start += data_size;
+            }
+        }
+        Ok(Self { desc: scan.desc, sources })
+    }
+
+    pub fn track_count(&self) -> usize { self.desc.moov_tracks.len() }
+
+    /// Media timescale of the track, the unit of the sample timestamps
+    pub fn timescale(&self, track: usize) -> u32 {
+        self.desc.moov_tracks.get(track).map(|x| x.mdhd_timescale).unwrap_or(0)
+    }
+
+    /// All samples, track by track, each track in decode order
+    pub fn iter(&self) -> impl Iterator<Item = SampleInfo> + '_ {
+        (0..self.track_count()).flat_map(|x| self.track(x))
+    }
+
+    /// Samples of a single track in decode order
+    pub fn track(&self, track: usize) -> TrackSamples<'_> {
+        let t = self.desc.moov_tracks.get(track);
+        TrackSamples {
+            table: self,
+            track,
+            samples_per_chunk: t.map(TrackDesc::samples_per_chunk).unwrap_or_default(),
+            index: 0,
+            chunk: 0,
+            in_chunk: 0,
+            offset: t.and_then(|x| x.stco.first().copied()).unwrap_or(0),
+            dts: 0,
+            stts: (0, 0),
+            ctts: (0, 0),
+            stss: 0,
+        }
+    }
+
+    /// Byte ranges of the chunks of `track`: index among the merged files, offset in that file and size
+    pub(crate) fn chunk_ranges(&self, track: usize) -> Vec<(usize, u64, u64)> {
+        let Some(t) = self.desc.moov_tracks.get(track) else { return Vec::new(); };
+        let mut sample = 0usize;
+        let mut ret = Vec::with_capacity(t.stco.len());
+        for (offset, samples) in t.stco.iter().zip(t.samples_per_chunk()) {
+            let size = (sample..sample + samples as usize).map(|x| t.sample_size(x) as u64).sum::<u64>();
+            sample += samples as usize;
+            if let Some(source) = self.source(*offset) {
+                ret.push((source.file, source.file_offset + (offset - source.data_start), size));
+            }
+        }
+        ret
+    }
+
+    /// Track whose sync samples are used for cutting: the first one which has them (usually video)
+    pub(crate) fn reference_track(&self) -> usize {
+        let tracks = &self.desc.moov_tracks;
+        (0..tracks.len()).find(|x| !tracks[*x].skip && !tracks[*x].stss.is_empty())
+            .or_else(|| (0..tracks.len()).find(|x| !tracks[*x].skip && tracks[*x].stsz_count > 0))
+            .unwrap_or(0)
+    }
+
+    /// Builds the description of a file containing only the samples in `ranges` (sample indices for each track, in order).
+    /// Tracks without ranges end up empty. The chunks keep their original order in the media data.
+    pub(crate) fn slice(&self, ranges: &[Vec<Range<u32>>]) -> Desc {
+        struct NewChunk { track: usize, file: usize, offset: u64, size: u64, samples: u32, description: u32, new_offset: u64 }
+
+        let mut desc = Desc {
+            mvhd_timescale_per_file: self.desc.mvhd_timescale_per_file.clone(),
+            mvhd_creation_time_per_file: self.desc.mvhd_creation_time_per_file.clone(),
+            moov_mvhd_timescale: self.desc.moov_mvhd_timescale,
+            ..Default::default()
+        };
+        let mut chunks: Vec<NewChunk> = Vec::new();
+        for (ti, t) in self.desc.moov_tracks.iter().enumerate() {
+            let mut nt = TrackDesc {
+                tkhd_duration: 0, mdhd_duration: 0, elst_segment_duration: 0,
+                sample_offset: 0, chunk_offset: 0, stsz_count: 0, co64_final_position: 0,
+                ..t.without_tables()
+            };
+            let keep = ranges.get(ti).map(|x| &x[..]).unwrap_or_default();
+            let has_sdtp = t.sdtp.len() == t.stsz_count as usize;
+            let mut prev_chunk = None;
+            let mut samples = self.track(ti);
+            while let Some(s) = samples.next() {
+                if !keep.iter().any(|r| r.contains(&s.index)) {
+                    prev_chunk = None;
+                    continue;
+                }
+                let chunk = samples.chunk;
+                if prev_chunk != Some(chunk) {
+                    let description = t.stsc.iter().take_while(|x| x.0 as usize <= chunk + 1).last().map(|x| x.2).unwrap_or(1);
+                    let file = self.source(s.merged_offset).map(|x| x.file).unwrap_or(0);
+                    chunks.push(NewChunk { track: ti, file, offset: s.source_offset, size: 0, samples: 0, description, new_offset: 0 });
+                    prev_chunk = Some(chunk);
+                }
+                if let Some(c) = chunks.last_mut() {
+                    c.size += s.size as u64;
+                    c.samples += 1;
+                }
+                if nt.stsz_sample_size == 0 { nt.stsz.push(s.size); }
+                nt.stsz_count += 1;
+                match nt.stts.last_mut() {
+                    Some(last) if last.1 == s.duration => last.0 += 1,
+                    _ => nt.stts.push((1, s.duration))
+                }
+                if !t.ctts.is_empty() {
+                    let offset = (s.pts - s.dts as i64) as i32;
+                    match nt.ctts.last_mut() {
+                        Some(last) if last.1 == offset => last.0 += 1,
+                        _ => nt.ctts.push((1, offset))
+                    }
+                }
+                if !t.stss.is_empty() && s.sync { nt.stss.push(nt.stsz_count); }
+                if has_sdtp { nt.sdtp.push(t.sdtp[s.index as usize]); }
+                nt.mdhd_duration += s.duration as u64;
+            }
+            nt.tkhd_duration = desc_reader::rescale(nt.mdhd_duration, nt.mdhd_timescale, desc.moov_mvhd_timescale);
+            if t.elst_segment_duration != 0 {
+                nt.elst_segment_duration = nt.tkhd_duration;
+            }
+            desc.moov_mvhd_duration = desc.moov_mvhd_duration.max(nt.tkhd_duration);
+            desc.moov_tracks.push(nt);
+        }
+
+        // Lay out the chunks in the order of the source, to keep the tracks interleaved
+        let mut order = (0..chunks.len()).collect::<Vec<_>>();
+        order.sort_by_key(|x| (chunks[*x].file, chunks[*x].offset));
+        let mut offset = 0;
+        for i in order {
+            let c = &mut chunks[i];
+            c.new_offset = offset;
+            offset += c.size;
+            match desc.mdat_position.last_mut() {
+                Some(last) if last.0 == Some(c.file) && last.1 + last.2 == c.offset => last.2 += c.size,
+                _ => desc.mdat_position.push((Some(c.file), c.offset, c.size))
+            }
+        }
+        for c in &chunks {
+            let t = &mut desc.moov_tracks[c.track];
+            t.stco.push(c.new_offset);
+            if t.stsc.last().map(|x| (x.1, x.2)) != Some((c.samples, c.description)) {
+                t.stsc.push((t.stco.len() as u32, c.samples, c.description));
+            }
+        }
+        desc.mdat_offset = offset;
+        desc
+    }
+
+    fn source(&self, merged_offset: u64) -> Option<&Source> {
+        let i = self.sources.partition_point(|x| x.data_start + x.data_size <= merged_offset);
+        self.sources.get(i).or(self.sources.last())
+    }
+}
+
+/// Iterator over the samples of a track, see [`SampleTable::track`]
+pub struct TrackSamples<'a> {
+    table: &'a SampleTable,
+    track: usize,
+    samples_per_chunk: Vec<u32>,
+    index: u32,
+    chunk: usize,
+    in_chunk: u32,
+    offset: u64,
+    dts: u64,
+    stts: (usize, u32), // entry, samples used from the entry
+    ctts: (usize, u32),
+    stss: usize,
+}
+
+impl Iterator for TrackSamples<'_> {
+    type Item = SampleInfo;
+    fn next(&mut self) -> Option<SampleInfo> {
+        let t = self.table.desc.moov_tracks.get(self.track)?;
+        if self.index >= t.stsz_count { return None; }
+        while self.in_chunk >= *self.samples_per_chunk.get(self.chunk)? {
+            self.chunk += 1;
+            self.in_chunk = 0;
+            self.offset = *t.stco.get(self.chunk)?;
+        }
+        let size = t.sample_size(self.index as usize);
+
+        let duration = match t.stts.get(self.stts.0) {
+            Some(x) => {
+                self.stts.1 += 1;
+                if self.stts.1 >= x.0 { self.stts = (self.stts.0 + 1, 0); }
+                x.1
+            },
+            None => 0
+        };
+        let composition_offset = match t.ctts.get(self.ctts.0) {
+            Some(x) => {
+                self.ctts.1 += 1;
+                if self.ctts.1 >= x.0 { self.ctts = (self.ctts.0 + 1, 0); }
+                x.1
+            },
+            None => 0
+        };
+        while t.stss.get(self.stss).is_some_and(|x| *x <= self.index) { self.stss += 1; }
+        let sync = t.stss.is_empty() || t.stss.get(self.stss) == Some(&(self.index + 1));
+
+        let source = self.table.source(self.offset)?;
+        let ret = SampleInfo {
+            track: self.track,
+            index: self.index,
+            dts: self.dts,
+            pts: self.dts as i64 + composition_offset as i64,
+            duration,
+            merged_offset: self.offset,
+            source: source.input,
+            source_offset: source.file_offset + (self.offset - source.data_start),
+            size,
+            sync,
+        };
+        self.index += 1;
+        self.in_chunk += 1;
+        self.offset += size as u64;
+        self.dts += duration as u64;
+        Some(ret)
+    }
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Write, Seek, SeekFrom, Result, Error, ErrorKind };
+use std::ops::Range;
+use std::path::{ Path, PathBuf };
+use crate::{ scan_files, writer, progress::Progress, read_provenance, AtomicOutput, MergeOptions, SampleTable, SampleInfo };
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SplitAt {
+    /// Split at these times in seconds. Each cut is moved back to the nearest sync sample
+    Times(Vec<f64>),
+    /// Start a new part on the last sync sample before the part would exceed this many bytes
+    MaxSize(u64),
+    /// Split into the original files recorded in the provenance box
+    Provenance,
+}
+
+/// Splits `input` into standalone files named `{name}_part01.{ext}`, `{name}_part02.{ext}`, ... next to it. Returns the paths of the parts.
+/// Each part is written through an [`AtomicOutput`], so a failed split doesn't leave a partially written part.
+pub fn split_file<P: AsRef<Path>, F: Fn(f64)>(input: &P, at: &SplitAt, progress_cb: F) -> Result<Vec<PathBuf>> {
+    let input = input.as_ref();
+    let f = std::fs::File::open(input)?;
+    let size = f.metadata()?.len() as usize;
+    let stem = input.file_stem().map(|x| x.to_string_lossy().into_owned()).unwrap_or_default();
+    let ext = input.extension().map(|x| x.to_string_lossy().into_owned()).unwrap_or_else(|| "mp4".into());
+    let finish = |mut x: AtomicOutput| { let size = x.file().metadata()?.len(); x.finish(size) };
+    let mut paths = Vec::new();
+    let mut current = None;
+    split_file_stream(&mut (f, size), at, |i| {
+        // The previous part is complete
+        if let Some(x) = current.take() { finish(x)?; }
+        let path = input.with_file_name(format!("{stem}_part{:02}.{ext}", i + 1));
+        let mut output = AtomicOutput::create(&path, &[input])?;
+        let f = output.file().try_clone()?;
+        current = Some(output);
+        paths.push(path);
+        Ok(f)
+    }, progress_cb)?;
+    if let Some(x) = current.take() { finish(x)?; }
+    Ok(paths)
+}
+
+/// Splits `input` into parts, `create_output` is called with the index of each part. Returns the number of parts.
+pub fn split_file_stream<I: Read + Seek, O: Read + Write + Seek, F: Fn(f64), C: FnMut(usize) -> Result<O>>(input: &mut (I, usize), at: &SplitAt, mut create_output: C, progress_cb: F) -> Result<usize> {
+    let provenance = if *at == SplitAt::Provenance {
+        Some(read_provenance(&mut input.0)?.ok_or_else(|| Error::new(ErrorKind::InvalidInput, "File has no provenance box"))?)
+    } else {
+        None
+    };
+    let scan = scan_files(std::slice::from_mut(input), &MergeOptions::default(), &mut |_| { })?;
+    let max_read = scan.insta360_max_read.unwrap_or(u64::MAX);
+    let input_size = scan.total_size as u64;
+    let table = SampleTable::from_scan(scan)?;
+    if table.track_count() == 0 {
+        return Err(crate::invalid_data("The file has no tracks to split"));
+    }
+    let samples = (0..table.track_count()).map(|x| table.track(x).collect::<Vec<_>>()).collect::<Vec<_>>();
+
+    // First sample of each part, for each track
+    let boundaries: Vec<Vec<u32>> = match (at, provenance) {
+        (_, Some(provenance)) => {
+            let mut ret = Vec::new();
+            for x in provenance.sources.iter().skip(1) {
+                if x.tracks.len() != samples.len() {
+                    return Err(crate::invalid_data("Provenance doesn't match the tracks of the file"));
+                }
+                ret.push(x.tracks.iter().map(|x| x.0).collect());
+            }
+            ret
+        },
+        (SplitAt::Times(times), _) => {
+            let reference = table.reference_track();
+            let timescale = table.timescale(reference).max(1) as f64;
+            let mut cuts = Vec::new();
+            for t in times {
+                let cut = samples[reference].iter().rev().find(|x| x.sync && x.index > 0 && x.dts as f64 / timescale <= *t);
+                if let Some(cut) = cut {
+                    if cuts.last().is_none_or(|x: &SampleInfo| x.index < cut.index) { cuts.push(*cut); }
+                }
+            }
+            cuts.iter().map(|x| cut_tracks(&table, &samples, reference, x)).collect()
+        },
+        (SplitAt::MaxSize(max_size), _) => {
+            let reference = table.reference_track();
+            // The tables of the whole file are an upper bound for the tables of a part, plus 4 bytes per chunk for stco -> co64
+            let media_size: u64 = samples.iter().flatten().map(|x| x.size as u64).sum();
+            let chunks: usize = table.desc.moov_tracks.iter().map(|x| x.stco.len()).sum();
+            let overhead = input_size.saturating_sub(media_size) + chunks as u64 * 4;
+            let part_size = |from: &[u32], to: &[u32]| overhead + samples.iter().zip(from).zip(to).map(|((s, a), b)| s[*a as usize..*b as usize].iter().map(|x| x.size as u64).sum::<u64>()).sum::<u64>();
+            let end = samples.iter().map(|x| x.len() as u32).collect::<Vec<_>>();
+
+            let mut ret: Vec<Vec<u32>> = Vec::new();
+            let mut start = vec![0u32; samples.len()];
+            let mut prev_cut: Option<Vec<u32>> = None;
+            let cuts = samples[reference].iter().filter(|x| x.sync && x.index > 0).map(|x| cut_tracks(&table, &samples, reference, x));
+            for next in cuts.chain(std::iter::once(end)) {
+                if part_size(&start, &next) > *max_size {
+                    if let Some(cut) = prev_cut.take() {
+                        if part_size(&start, &cut) > *max_size {
+                            log::warn!("Part {} exceeds the maximum size, there's no sync sample to split at", ret.len() + 1);
+                        }
+                        start = cut.clone();
+                        ret.push(cut);
+                    }
+                }
+                prev_cut = Some(next);
+            }
+            ret
+        },
+        (SplitAt::Provenance, None) => unreachable!()
+    };
+
+    let parts = boundaries.len() + 1;
+    for part in 0..parts {
+        let ranges = (0..samples.len()).map(|t| {
+            let track = &table.desc.moov_tracks[t];
+            let range = if track.skip {
+                0..track.stsz_count // e.g. timecode, which isn't split
+            } else {
+                let start = if part > 0 { boundaries[part - 1][t] } else { 0 };
+                start..boundaries.get(part).map(|x| x[t]).unwrap_or(track.stsz_count).max(start)
+            };
+            vec![range]
+        }).collect::<Vec<Vec<Range<u32>>>>();
+        let mut desc = table.slice(&ranges);
+
+        let mut f_out = std::io::BufWriter::with_capacity(64*1024, create_output(part)?);
+        let files = &mut [(&mut input.0, input.1)];
+        writer::get_first(files)?.seek(SeekFrom::Start(0))?;
+        writer::rewrite_from_desc(files, &mut f_out, &mut desc, &mut Progress::new(&mut |_| { }), 0, max_read)?;
+        writer::patch_chunk_offsets(&mut f_out, &desc)?;
+        f_out.flush()?;
+        progress_cb((part + 1) as f64 / parts as f64);
+    }
+    Ok(parts)
+}
+
+/// First sample of each track at or after the time of `cut`
+pub(crate) fn cut_tracks(table: &SampleTable, samples: &[Vec<SampleInfo>], reference: usize, cut: &SampleInfo) -> Vec<u32> {
+    let reference_timescale = table.timescale(reference) as u128;
+    samples.iter().enumerate().map(|(t, s)| {
+        if t == reference { return cut.index; }
+        let timescale = table.timescale(t) as u128;
+        // dts / timescale >= cut.dts / reference_timescale
+        s.partition_point(|x| (x.dts as u128) * reference_timescale < (cut.dts as u128) * timescale) as u32
+    }).collect()
+}
@@ -0,0 +1,246 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::collections::{ BTreeMap, VecDeque };
+use std::io::{ Read, Write, Seek, SeekFrom, Result, ErrorKind };
+use std::time::Instant;
+use crate::{ cancel, checksum::MediaChecksums, invalid_data, merge_streams, progress::Progress, CancellationToken, DeferredMedia, MergeOptions, MergeReport, ProgressEvent };
+
+/// Merges to an output which is written strictly sequentially. Everything except the media data is planned in memory
+/// by the regular merge, with gaps which are then filled with the media data while writing the output.
+pub(crate) fn merge_to_writer<I: Read + Seek, O: Write>(files: &mut [(I, usize)], mut output_file: O, options: &MergeOptions, progress_cb: &mut dyn FnMut(ProgressEvent)) -> Result<MergeReport> {
+    if options.journal.is_some() {
+        return Err(std::io::Error::new(ErrorKind::Unsupported, "Resuming needs a seekable output"));
+    }
+    let mut layout = SparseStream::default();
+    let mut media = DeferredMedia::default();
+    let mut report = merge_streams(files, &mut layout, options, &mut |event| if event != ProgressEvent::Finished { progress_cb(event) }, None, Some(&mut media))?;
+
+    let copy_start = Instant::now();
+    let mut copy = MediaCopy::new(media, options, Progress::new(progress_cb));
+    let mut pos = 0;
+    for (offset, data) in std::mem::take(&mut layout.regions) {
+        copy.copy(files, &mut output_file, offset - pos, options.cancel.as_ref())?;
+        output_file.write_all(&data)?;
+        pos = offset + data.len() as u64;
+    }
+    copy.copy(files, &mut output_file, report.output_size.saturating_sub(pos), options.cancel.as_ref())?;
+    output_file.flush()?;
+
+    let mut progress = copy.finish(&mut report)?;
+    report.write_time += copy_start.elapsed();
+    progress.event(ProgressEvent::Finished);
+    Ok(report)
+}
+
+/// Copies the media data into the gaps of a planned output, in the order of the merge
+pub(crate) struct MediaCopy<'a, C: FnMut(ProgressEvent) + ?Sized> {
+    ranges: VecDeque<(usize, u64, u64)>, // merged file, offset, size
+    inputs: Vec<usize>, // index in the input list of each merged file
+    pub buf: Vec<u8>,
+    checksums: Option<MediaChecksums>,
+    progress: Progress<'a, C>,
+}
+
+impl<'a, C: FnMut(ProgressEvent) + ?Sized> MediaCopy<'a, C> {
+    pub fn new(media: DeferredMedia, options: &MergeOptions, mut progress: Progress<'a, C>) -> Self {
+        progress.set_media(&media.ranges, media.inputs.clone());
+        Self {
+            ranges: media.ranges.iter().filter_map(|x| Some((x.0?, x.1, x.2))).filter(|x| x.0 < media.inputs.len()).collect(),
+            inputs: media.inputs,
+            buf: vec![0u8; if options.buffer_size > 0 { options.buffer_size } else { 64*1024 }],
+            checksums: options.checksum.map(MediaChecksums::new),
+            progress,
+        }
+    }
+
+    /// Takes the next range of at most `size` bytes to copy, returns `(input, merged file, offset, size)`
+    pub fn next(&mut self, size: u64) -> Result<(usize, usize, u64, u64)> {
+        let Some((file, offset, left)) = self.ranges.front_mut() else {
+            return Err(invalid_data("The media data doesn't fill the output"));
+        };
+        let len = size.min(*left);
+        let range = (self.inputs[*file], *file, *offset, len);
+        (*offset, *left) = (*offset + len, *left - len);
+        if *left == 0 { self.ranges.pop_front(); }
+        Ok(range)
+    }
+
+    /// Reports the first `size` bytes of `buf`, copied from merged `file`
+    pub fn copied(&mut self, file: usize, size: usize) {
+        if let Some(checksums) = &mut self.checksums { checksums.update(file, &self.buf[..size]); }
+        self.progress.copied(file, size as u64);
+    }
+
+    pub fn copy<I: Read + Seek, O: Write>(&mut self, files: &mut [(I, usize)], output: &mut O, mut size: u64, cancel: Option<&CancellationToken>) -> Result<()> {
+        while size > 0 {
+            let (input, file, offset, mut len) = self.next(size)?;
+            size -= len;
+            let f = &mut files[input].0;
+            f.seek(SeekFrom::Start(offset))?;
+            while len > 0 {
+                cancel::check(cancel)?;
+                let n = len.min(self.buf.len() as u64) as usize;
+                f.read_exact(&mut self.buf[..n])?;
+                output.write_all(&self.buf[..n])?;
+                self.copied(file, n);
+                len -= n as u64;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that all media data was copied and fills the checksums in `report`. Returns the progress for the last event
+    pub fn finish(self, report: &mut MergeReport) -> Result<Progress<'a, C>> {
+        if !self.ranges.is_empty() {
+            return Err(invalid_data("The output has no space left for the media data"));
+        }
+        if let Some(checksums) = self.checksums {
+            let (total, per_file) = checksums.finish();
+            for (x, checksum) in report.inputs.iter_mut().zip(per_file) {
+                x.checksum = checksum;
+            }
+            report.checksum = Some(total);
+        }
+        Ok(self.progress)
+    }
+}
+
+/// Stream which holds only some regions of its data in memory. Reading anything else fails.
+#[derive(Default)]
+pub(crate) struct SparseStream {
+    pub regions: BTreeMap<u64, Vec<u8>>,
+    pub pos: u64,
+    pub len: u64,
+}
+
+impl Read for SparseStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() || self.pos >= self.len { return Ok(0); }
+        let Some((start, data)) = self.regions.range(..=self.pos).next_back() else {
+            return Err(invalid_data("Reading data which wasn't loaded"));
+        };
+        let data = data.get((self.pos - start) as usize..).filter(|x| !x.is_empty()).ok_or_else(|| invalid_data("Reading data which wasn't loaded"))?;
+        let read = data.len().min(buf.len());
+        buf[..read].copy_from_slice(&data[..read]);
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for SparseStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        // Extend the region which contains or ends at the position, then merge the following ones into it
+        let start = match self.regions.range(..=self.pos).next_back() {
+            Some((start, data)) if start + data.len() as u64 >= self.pos => *start,
+            _ => self.pos
+        };
+        let mut data = self.regions.remove(&start).unwrap_or_default();
+        let at = (self.pos - start) as usize;
+        if data.len() < at + buf.len() { data.resize(at + buf.len(), 0); }
+        data[at..at + buf.len()].copy_from_slice(buf);
+        let end = start + data.len() as u64;
+        while let Some((&next, _)) = self.regions.range(start..=end).next() {
+            let next_data = self.regions.remove(&next).unwrap();
+            if let Some(tail) = next_data.get((end - next) as usize..) { data.extend_from_slice(tail); }
+        }
+        self.regions.insert(start, data);
+        self.pos += buf.len() as u64;
+        self.len = self.len.max(self.pos);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<()> { Ok(()) }
+}
+
+impl Seek for SparseStream {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => self.len.checked_add_signed(x),
+            SeekFrom::Current(x) => self.pos.checked_add_signed(x),
+        };
+        self.pos = pos.ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "Invalid seek"))?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_at(stream: &mut SparseStream, pos: u64, data: &[u8]) {
+        stream.seek(SeekFrom::Start(pos)).unwrap();
+        stream.write_all(data).unwrap();
+    }
+
+    fn read_at(stream: &mut SparseStream, pos: u64, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len];
+        stream.seek(SeekFrom::Start(pos))?;
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn disjoint_regions() {
+        let mut s = SparseStream::default();
+        write_at(&mut s, 0, b"abcd");
+        write_at(&mut s, 10, b"xyz");
+        assert_eq!(s.regions.len(), 2);
+        assert_eq!(s.len, 13);
+        assert_eq!(read_at(&mut s, 1, 3).unwrap(), b"bcd");
+        assert_eq!(read_at(&mut s, 10, 3).unwrap(), b"xyz");
+        assert!(read_at(&mut s, 4, 1).is_err());
+        assert!(read_at(&mut s, 2, 4).is_err());
+    }
+
+    #[test]
+    fn adjacent_writes_merge() {
+        let mut s = SparseStream::default();
+        write_at(&mut s, 4, b"efgh");
+        write_at(&mut s, 0, b"abcd");
+        write_at(&mut s, 8, b"ij");
+        assert_eq!(s.regions.len(), 1);
+        assert_eq!(s.regions[&0], b"abcdefghij");
+    }
+
+    #[test]
+    fn overwrite_inside_region() {
+        let mut s = SparseStream::default();
+        write_at(&mut s, 0, b"abcdef");
+        write_at(&mut s, 2, b"XY");
+        assert_eq!(s.regions.len(), 1);
+        assert_eq!(s.regions[&0], b"abXYef");
+        assert_eq!(s.len, 6);
+        assert_eq!(s.pos, 4);
+    }
+
+    #[test]
+    fn write_bridges_regions() {
+        let mut s = SparseStream::default();
+        write_at(&mut s, 0, b"abc");
+        write_at(&mut s, 6, b"ghi");
+        write_at(&mut s, 12, b"mno");
+        // Overlaps the end of the first region and the start of the second one, the written data wins
+        write_at(&mut s, 2, b"CDEFGH");
+        assert_eq!(s.regions.len(), 2);
+        assert_eq!(s.regions[&0], b"abCDEFGHi");
+        assert_eq!(s.regions[&12], b"mno");
+        // Covers the rest
+        write_at(&mut s, 9, b"jkl");
+        assert_eq!(s.regions.len(), 1);
+        assert_eq!(s.regions[&0], b"abCDEFGHijklmno");
+    }
+
+    #[test]
+    fn seek_past_the_end() {
+        let mut s = SparseStream { len: 100, ..Default::default() };
+        assert_eq!(s.seek(SeekFrom::End(-10)).unwrap(), 90);
+        assert_eq!(s.seek(SeekFrom::Current(-5)).unwrap(), 85);
+        assert!(s.seek(SeekFrom::Current(-100)).is_err());
+        write_at(&mut s, 98, b"abcd");
+        assert_eq!(s.len, 102);
+        let mut buf = [0u8; 8];
+        s.seek(SeekFrom::Start(102)).unwrap();
+        assert_eq!(s.read(&mut buf).unwrap(), 0);
+    }
+}
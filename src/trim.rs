@@ -0,0 +1,113 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::Result;
+use std::ops::Range;
+use crate::{ desc_reader::{ self, Desc }, invalid_data, SampleTable, SampleInfo, InputReport };
+
+/// Time range to keep when merging, see [`MergeOptions::keep`](crate::MergeOptions::keep)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    /// Index of the input file the times are relative to, or `None` for the merged timeline
+    pub input: Option<usize>,
+    /// Start and end in seconds
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Keeps only the samples in `keep` (everything if it's empty) of `tracks` (all if `None`). Each range starts at the sync sample
+/// preceding its start, and with `edit_list` the samples before the requested start are hidden with an edit list.
+pub(crate) fn trim(table: &SampleTable, inputs: &[InputReport], keep: &[TimeRange], edit_list: bool, tracks: Option<&[usize]>) -> Result<Desc> {
+    if !keep.is_empty() && table.track_count() == 0 {
+        return Err(invalid_data("The inputs have no tracks to trim"));
+    }
+    let movie_timescale = table.desc.moov_mvhd_timescale.max(1) as f64;
+    let mut keep = keep.iter().filter_map(|x| {
+        let offset = match x.input {
+            Some(input) => inputs.iter().find(|i| i.index == input)?.start_time as f64 / movie_timescale,
+            None => 0.0
+        };
+        Some((x.start.max(0.0) + offset, x.end + offset)).filter(|x| x.1 > x.0)
+    }).collect::<Vec<_>>();
+    keep.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let samples = (0..table.track_count()).map(|x| table.track(x).collect::<Vec<_>>()).collect::<Vec<_>>();
+    let reference = table.reference_track();
+    let reference_timescale = table.timescale(reference).max(1) as f64;
+    let time = |s: &SampleInfo, timescale: f64| s.dts as f64 / timescale;
+
+    let mut ranges = vec![Vec::<Range<u32>>::new(); samples.len()];
+    let mut edits = vec![Vec::new(); samples.len()];
+    let mut kept_duration = vec![0u64; samples.len()]; // of the samples kept so far, in track timescale
+    if keep.is_empty() {
+        for (r, s) in ranges.iter_mut().zip(&samples) { r.push(0..s.len() as u32); }
+    }
+    for (start, end) in keep {
+        let reference_samples = &samples[reference];
+        let Some(sync) = reference_samples.iter().rev().find(|x| x.sync && time(x, reference_timescale) <= start).or(reference_samples.first()) else { continue; };
+        let sync_time = time(sync, reference_timescale);
+        for (t, s) in samples.iter().enumerate() {
+            let track = &table.desc.moov_tracks[t];
+            if track.skip {
+                if ranges[t].is_empty() { ranges[t].push(0..track.stsz_count); }
+                continue;
+            }
+            let timescale = table.timescale(t).max(1) as f64;
+            let mut first = if t == reference { sync.index as usize } else { s.partition_point(|x| time(x, timescale) < sync_time) };
+            let last = s.partition_point(|x| time(x, timescale) < end);
+            // Ranges which overlap after moving back to the sync sample
+            if let Some(prev) = ranges[t].last() {
+                first = first.max(prev.end as usize);
+            }
+            if first >= last { continue; }
+            let range = first as u32..last as u32;
+
+            let media_start = kept_duration[t];
+            let range_duration: u64 = s[range.start as usize..range.end as usize].iter().map(|x| x.duration as u64).sum();
+            kept_duration[t] += range_duration;
+            if edit_list {
+                // Hide the pre-roll before the requested start, or fill the gap if the track starts later, to keep the tracks in sync
+                let first_time = time(&s[first], timescale);
+                let last_end = time(&s[last - 1], timescale) + s[last - 1].duration as f64 / timescale;
+                if first_time > start {
+                    edits[t].push((((first_time - start) * movie_timescale).round() as u64, -1));
+                }
+                let skip = (((start - first_time).max(0.0) * timescale).round() as u64).min(range_duration);
+                let duration = ((last_end.min(end) - start.max(first_time)) * movie_timescale).round().max(0.0) as u64;
+                // Media time is in presentation time, so it includes the composition offset
+                let composition_offset = s[first].pts - s[first].dts as i64;
+                edits[t].push((duration, (media_start + skip) as i64 + composition_offset));
+            }
+            match ranges[t].last_mut() {
+                Some(prev) if prev.end == range.start => prev.end = range.end,
+                _ => ranges[t].push(range)
+            }
+        }
+    }
+
+    let dropped = |t: usize| tracks.is_some_and(|x| !x.contains(&t));
+    for (t, r) in ranges.iter_mut().enumerate() {
+        if dropped(t) { r.clear(); }
+    }
+
+    let mut desc = table.slice(&ranges);
+    for (t, x) in desc.moov_tracks.iter_mut().enumerate() {
+        x.excluded = dropped(t);
+    }
+    if edit_list {
+        desc.moov_mvhd_duration = 0;
+        for (t, edits) in desc.moov_tracks.iter_mut().zip(edits) {
+            // Only existing edit lists are rewritten, the durations of other tracks have to match their media
+            if edits.is_empty() || t.excluded || t.elst_segment_duration == 0 { continue; }
+            t.tkhd_duration = edits.iter().map(|x| x.0).sum();
+            t.edits = edits;
+            desc.moov_mvhd_duration = desc.moov_mvhd_duration.max(t.tkhd_duration);
+        }
+        for t in &desc.moov_tracks {
+            if t.edits.is_empty() {
+                desc.moov_mvhd_duration = desc.moov_mvhd_duration.max(desc_reader::rescale(t.mdhd_duration, t.mdhd_timescale, desc.moov_mvhd_timescale));
+            }
+        }
+    }
+    Ok(desc)
+}
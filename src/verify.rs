@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+use std::io::{ Read, Seek, SeekFrom, Result };
+use std::path::Path;
+use crate::{ sample_table_file_streams, SampleTable, provider::{ Paths, Pool, MAX_OPEN_FILES }, SampleInfo };
+
+// Mismatches reported per track before giving up on it
+const MAX_MISMATCHES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerifyMode {
+    /// Compare only the sample tables
+    Tables,
+    /// Also compare the bytes of this many samples of each track, evenly spread
+    Spot(usize),
+    /// Also compare the bytes of every sample
+    #[default]
+    Full,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Number of samples whose bytes were compared
+    pub samples_checked: u64,
+    /// Description of each difference found
+    pub mismatches: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool { self.mismatches.is_empty() }
+}
+
+/// Checks that `output` contains exactly the samples of `inputs` merged, e.g. after [`join_files`](crate::join_files)
+pub fn verify_merge<P: AsRef<Path>, F: Fn(f64)>(output: &P, inputs: &[P], mode: VerifyMode, progress_cb: F) -> Result<VerifyReport> {
+    let f = std::fs::File::open(output)?;
+    let size = f.metadata()?.len() as usize;
+    let mut output = (std::io::BufReader::with_capacity(64*1024, f), size);
+    // Opened when they are read, like the inputs of the merge
+    let pool = Pool::new(MAX_OPEN_FILES);
+    let mut inputs = Pool::add(&pool, Paths(inputs.iter().map(|x| x.as_ref().to_owned()).collect()))?.into_iter().map(|(x, size)| (std::io::BufReader::with_capacity(64*1024, x), size)).collect::<Vec<_>>();
+    verify_merge_streams(&mut output, &mut inputs, mode, progress_cb)
+}
+
+pub fn verify_merge_streams<O: Read + Seek, I: Read + Seek, F: Fn(f64)>(output: &mut (O, usize), inputs: &mut [(I, usize)], mode: VerifyMode, progress_cb: F) -> Result<VerifyReport> {
+    let actual = sample_table_file_streams(std::slice::from_mut(output))?;
+    let expected = sample_table_file_streams(inputs)?;
+    let mut report = VerifyReport::default();
+
+    if actual.track_count() != expected.track_count() {
+        mismatch(&mut report, format!("The output has {} tracks, expected {}", actual.track_count(), expected.track_count()));
+        return Ok(report);
+    }
+    let total_samples = expected.desc.moov_tracks.iter().map(|x| x.stsz_count as u64).sum::<u64>().max(1);
+    let mut done = 0;
+    let mut buf = (Vec::new(), Vec::new());
+    let chunks = (packed_chunks(&actual), packed_chunks(&expected));
+    for t in 0..expected.track_count() {
+        let (a, e) = (&actual.desc.moov_tracks[t], &expected.desc.moov_tracks[t]);
+        if a.stsz_count != e.stsz_count {
+            mismatch(&mut report, format!("Track {t}: {} samples, expected {}", a.stsz_count, e.stsz_count));
+            continue;
+        }
+        if a.mdhd_duration != e.mdhd_duration || a.tkhd_duration != e.tkhd_duration {
+            mismatch(&mut report, format!("Track {t}: duration {}/{}, expected {}/{} (mdhd/tkhd)", a.mdhd_duration, a.tkhd_duration, e.mdhd_duration, e.tkhd_duration));
+        }
+        if chunks.0[t] != chunks.1[t] {
+            let chunk = chunks.0[t].iter().zip(&chunks.1[t]).position(|(a, e)| a != e).unwrap_or(a.stco.len().min(e.stco.len()));
+            mismatch(&mut report, format!("Track {t}: {} chunks, expected {}, first difference at chunk {chunk}", a.stco.len(), e.stco.len()));
+        }
+
+        let count = e.stsz_count as u64;
+        let spot = |i: u64| match mode {
+            VerifyMode::Tables => false,
+            VerifyMode::Spot(n) => n > 0 && (n as u64 >= count || i.is_multiple_of((count / n as u64).max(1)) || i == count - 1),
+            VerifyMode::Full => true
+        };
+        let mut track_mismatches = 0;
+        for (a, e) in actual.track(t).zip(expected.track(t)) {
+            done += 1;
+            if done % 1000 == 0 { progress_cb(done as f64 / total_samples as f64); }
+            if track_mismatches >= MAX_MISMATCHES { continue; }
+            if (a.size, a.duration, a.pts - a.dts as i64, a.sync) != (e.size, e.duration, e.pts - e.dts as i64, e.sync) {
+                mismatch(&mut report, format!("Track {t}: sample {} has size {}, duration {}, composition offset {}, sync {}, expected {}, {}, {}, {}", e.index,
+                    a.size, a.duration, a.pts - a.dts as i64, a.sync, e.size, e.duration, e.pts - e.dts as i64, e.sync));
+                track_mismatches += 1;
+                continue;
+            }
+            if !spot(e.index as u64) { continue; }
+            read_sample(&mut output.0, &a, &mut buf.0)?;
+            let input = &mut inputs.get_mut(e.source).ok_or_else(|| crate::invalid_data("Invalid sample source"))?.0;
+            read_sample(input, &e, &mut buf.1)?;
+            report.samples_checked += 1;
+            if buf.0 != buf.1 {
+                mismatch(&mut report, format!("Track {t}: data of sample {} differs from input {} at offset {}", e.index, e.source, e.source_offset));
+                track_mismatches += 1;
+            }
+        }
+    }
+    progress_cb(1.0);
+    Ok(report)
+}
+
+/// Chunk offsets of each track relative to the start of the media data, without the bytes which no sample uses
+/// (e.g. the old `moov` inside the `mdat` of a file which was appended to)
+fn packed_chunks(table: &SampleTable) -> Vec<Vec<u64>> {
+    let mut samples = (0..table.track_count()).flat_map(|t| table.track(t)).map(|x| (x.merged_offset, x.merged_offset + x.size as u64)).collect::<Vec<_>>();
+    samples.sort_unstable();
+    let mut used: Vec<(u64, u64, u64)> = Vec::new(); // start, end, unused bytes before
+    for (start, end) in samples {
+        match used.last_mut() {
+            Some(x) if start <= x.1 => x.1 = x.1.max(end),
+            last => {
+                let unused = last.map_or(start, |x| x.2 + start - x.1);
+                used.push((start, end, unused));
+            }
+        }
+    }
+    table.desc.moov_tracks.iter().map(|t| t.stco.iter().map(|&x| {
+        match used.partition_point(|r| r.0 <= x).checked_sub(1).map(|i| used[i]) {
+            Some((_, end, unused)) => x.min(end) - unused,
+            None => 0
+        }
+    }).collect()).collect()
+}
+
+fn mismatch(report: &mut VerifyReport, x: String) {
+    log::warn!("{x}");
+    report.mismatches.push(x);
+}
+
+fn read_sample<R: Read + Seek>(reader: &mut R, sample: &SampleInfo, buf: &mut Vec<u8>) -> Result<()> {
+    buf.resize(sample.size as usize, 0);
+    if reader.stream_position()? != sample.source_offset {
+        reader.seek(SeekFrom::Start(sample.source_offset))?;
+    }
+    reader.read_exact(buf)
+}
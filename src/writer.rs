@@ -1,39 +1,92 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 // Copyright © 2022 Adrian <adrian.eddy at gmail>
 
-use std::io::{ Read, Write, Seek, Result, SeekFrom };
+use std::io::{ Read, Write, Seek, Result, SeekFrom, Cursor };
 use byteorder::{ ReadBytesExt, WriteBytesExt, BigEndian };
-use crate::{ fourcc, read_box, typ_to_str, desc_reader::Desc };
+use crate::{ fourcc, read_box, typ_to_str, invalid_data, desc_reader::{ Desc, MAX_IN_MEMORY }, checksum::MediaChecksums, kernel_copy, pipeline, progress::{ Progress, ProgressEvent, MediaRead } };
 
-pub(crate) fn get_first<R: Read + Seek>(files: &mut [(R, usize)]) -> &mut R { files.get_mut(0).map(|x| &mut x.0).unwrap() }
+pub(crate) fn get_first<R: Read + Seek>(files: &mut [(R, usize)]) -> Result<&mut R> { files.get_mut(0).map(|x| &mut x.0).ok_or_else(|| invalid_data("No input files")) }
 
-pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], output_file: &mut W, desc: &mut Desc, track: usize, max_read: u64) -> Result<u64> {
-    let mut total_read_size = 0;
-    let mut total_new_size = 0;
+pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], output_file: &mut W, desc: &mut Desc, progress: &mut Progress, track: usize, max_read: u64) -> Result<u64> {
+    rewrite_boxes(files, output_file, desc, progress, track, max_read, false)
+}
+
+/// `in_memory` is set when `files` is a `moov` which was already read into memory
+fn rewrite_boxes<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize)], output_file: &mut W, desc: &mut Desc, progress: &mut Progress, track: usize, max_read: u64, in_memory: bool) -> Result<u64> {
+    let mut total_read_size = 0u64;
+    let mut total_new_size = 0u64;
     let mut tl_track = track;
-    while let Ok((typ, offs, size, header_size)) = read_box(get_first(files)) {
-        if size == 0 || typ == 0 { break; }
+    let mut moved_moov = None; // offset of moov written before mdat
+    let file_size = {
+        let d = get_first(files)?;
+        let pos = d.stream_position()?;
+        let end = d.seek(SeekFrom::End(0))?;
+        d.seek(SeekFrom::Start(pos))?;
+        end
+    };
+    while let Ok((typ, offs, size, header_size)) = read_box(get_first(files)?) {
+        if typ == 0 { break; }
+        if size > max_read - total_read_size || (typ != fourcc("mdat") && offs.saturating_add(size) > file_size) {
+            log::warn!("{} at offset {offs} exceeds its parent box or the file size", typ_to_str(typ));
+            break;
+        }
 
-        total_read_size += size;
+        total_read_size = total_read_size.saturating_add(size);
         let mut new_size = size;
-        if crate::has_children(typ, false) {
-            let d = get_first(files);
+        if moved_moov == Some(offs) {
+            log::debug!("Skipping moov written before mdat, offset: {}, size: {size}", offs);
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
+            new_size = 0;
+        } else if typ == fourcc("trak") && desc.moov_tracks.get(tl_track).is_some_and(|x| x.excluded) {
+            log::debug!("Dropping trak {tl_track}, offset: {}, size: {size}", offs);
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
+            tl_track += 1;
+            new_size = 0;
+        } else if typ == fourcc("moov") && !in_memory && size <= MAX_IN_MEMORY {
+            // Read and rebuilt in memory, then written at once, so the boxes are parsed and their sizes patched without seeking
+            let d = get_first(files)?;
+            d.seek(SeekFrom::Current(-header_size))?;
+            let mut moov = vec![0u8; size as usize];
+            d.read_exact(&mut moov)?;
+            let mut output = OutputBuffer { data: Cursor::new(Vec::new()), base: output_file.stream_position()? };
+            new_size = rewrite_boxes(&mut [(Cursor::new(moov), size as usize)], &mut output, desc, progress, tl_track, size, true)?;
+            output_file.write_all(output.data.get_ref())?;
+        } else if crate::has_children(typ, false) {
+            if typ == fourcc("moov") { progress.event(ProgressEvent::WritingTables); }
+            let d = get_first(files)?;
             // Copy the header
             d.seek(SeekFrom::Current(-header_size))?;
             let out_pos = output_file.stream_position()?;
             std::io::copy(&mut d.take(header_size as u64), output_file)?;
-            new_size = rewrite_from_desc(files, output_file, desc, tl_track, size - header_size as u64)?;
+            new_size = rewrite_boxes(files, output_file, desc, progress, tl_track, size - header_size as u64, in_memory)?;
             new_size += header_size as u64;
 
             if typ == fourcc("trak") {
                 tl_track += 1;
             }
+            if typ == fourcc("moov") {
+                if let Some(provenance) = &desc.provenance {
+                    output_file.write_all(provenance)?;
+                    new_size += provenance.len() as u64;
+                }
+            }
 
             if new_size != size {
                 log::debug!("Patching size from {size} to {new_size}");
                 patch_bytes(output_file, out_pos, &(new_size as u32).to_be_bytes())?;
             }
         } else if typ == fourcc("mdat") {
+            if desc.fast_start && moved_moov.is_none() {
+                let d = get_first(files)?;
+                let mdat_payload = d.stream_position()?;
+                if let Some((moov_offset, moov_size)) = find_box(d, offs.saturating_add(size), file_size.min(max_read), fourcc("moov"))? {
+                    log::debug!("Writing moov before mdat, offset: {moov_offset}, size: {moov_size}");
+                    d.seek(SeekFrom::Start(moov_offset))?;
+                    total_new_size += rewrite_boxes(files, output_file, desc, progress, tl_track, moov_size, in_memory)?;
+                    moved_moov = Some(moov_offset);
+                }
+                get_first(files)?.seek(SeekFrom::Start(mdat_payload))?;
+            }
             log::debug!("Merging mdat's, offset: {}, size: {size}", offs);
 
             output_file.write_all(&1u32.to_be_bytes())?;
@@ -45,24 +98,63 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
             desc.mdat_final_position = output_file.stream_position()?;
 
             // Merge all mdats
+            let mut skip = desc.mdat_resume;
+            let mut checksums = desc.checksum.map(MediaChecksums::new);
             for (file_index, mo, ms) in &desc.mdat_position {
                 if let Some(file_index) = file_index {
                     if let Some(f) = files.get_mut(*file_index).map(|x| &mut x.0) {
+                        // Already written by an interrupted merge
+                        let skipped = skip.min(*ms);
+                        skip -= skipped;
+                        if skipped > 0 { output_file.seek(SeekFrom::Current(skipped as i64))?; }
+
                         let prev_pos = f.stream_position()?;
-                        f.seek(SeekFrom::Start(*mo))?;
-                        std::io::copy(&mut f.take(*ms), output_file)?;
+                        let cancel = desc.cancel.as_ref();
+                        if let Some(checksums) = &mut checksums {
+                            // The skipped data is read only for the checksums
+                            f.seek(SeekFrom::Start(*mo))?;
+                            checksums.copy(*file_index, &mut MediaRead { inner: f.by_ref().take(skipped), file: *file_index, progress, cancel }, None::<&mut W>)?;
+                            checksums.copy(*file_index, &mut MediaRead { inner: f.by_ref().take(*ms - skipped), file: *file_index, progress, cancel }, Some(&mut *output_file))?;
+                        } else {
+                            progress.skipped(*file_index, skipped);
+                            let (handles, range) = (desc.files.as_deref(), (*file_index, *mo + skipped, *ms - skipped));
+                            if !kernel_copy::copy(handles, range, output_file, progress, cancel)? &&
+                               !pipeline::copy(handles, range, output_file, desc.copy_buffer_size, progress, cancel)? {
+                                f.seek(SeekFrom::Start(*mo + skipped))?;
+                                std::io::copy(&mut MediaRead { inner: f.by_ref().take(*ms - skipped), file: *file_index, progress, cancel }, output_file)?;
+                            }
+                        }
                         f.seek(SeekFrom::Start(prev_pos))?;
                         new_size += ms;
                     }
                 }
             }
+            if let Some(checksums) = checksums {
+                let (total, per_file) = checksums.finish();
+                (desc.media_checksum, desc.checksums) = (Some(total), per_file);
+            }
             patch_bytes(output_file, pos, &new_size.to_be_bytes())?;
 
-            get_first(files).seek(SeekFrom::Current(size as i64 - header_size))?;
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
 
+        } else if typ == fourcc("elst") && desc.moov_tracks.get(tl_track).is_some_and(|x| !x.edits.is_empty()) {
+            log::debug!("Writing new elst, offset: {}, size: {size}", offs);
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
+
+            let edits = &desc.moov_tracks[tl_track].edits;
+            new_size = 16 + edits.len() as u64 * 20;
+            output_file.write_u32::<BigEndian>(new_size as u32)?;
+            output_file.write_all(&fourcc("elst").to_be_bytes())?;
+            output_file.write_u32::<BigEndian>(1 << 24)?; // version 1, flags
+            output_file.write_u32::<BigEndian>(edits.len() as u32)?;
+            for (segment_duration, media_time) in edits {
+                output_file.write_u64::<BigEndian>(*segment_duration)?;
+                output_file.write_i64::<BigEndian>(*media_time)?;
+                output_file.write_u32::<BigEndian>(0x10000)?; // media rate 1.0
+            }
         } else if typ == fourcc("mvhd") || typ == fourcc("tkhd") || typ == fourcc("mdhd") || typ == fourcc("elst") {
             log::debug!("Writing {} with patched duration, offset: {}, size: {size}", typ_to_str(typ), offs);
-            let d = get_first(files);
+            let d = get_first(files)?;
 
             let (v, _flags) = (d.read_u8()?, d.read_u24::<BigEndian>()?);
 
@@ -96,25 +188,38 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                 }
             }
 
-        } else if typ == fourcc("stts") || typ == fourcc("stsz") || typ == fourcc("stss") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") {
+        } else if typ == fourcc("uuid") && crate::provenance::is_provenance(get_first(files)?, size - header_size as u64)? {
+            // Describes the sources of the input, not of the output
+            log::debug!("Dropping provenance, offset: {}, size: {size}", offs);
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
+            new_size = 0;
+        } else if typ == fourcc("ctts") && desc.moov_tracks.get(tl_track).is_some_and(|x| x.ctts.is_empty()) {
+            // Composition offsets are unknown, e.g. in a recovered file
+            log::debug!("Dropping empty ctts, offset: {}, size: {size}", offs);
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
+            new_size = 0;
+        } else if typ == fourcc("stts") || typ == fourcc("stsz") || typ == fourcc("stss") || typ == fourcc("stco") || typ == fourcc("co64") || typ == fourcc("sdtp") || typ == fourcc("stsc") || typ == fourcc("ctts") {
             log::debug!("Writing new {}, offset: {}, size: {size}", typ_to_str(typ), offs);
 
-            get_first(files).seek(SeekFrom::Current(size as i64 - header_size))?;
+            get_first(files)?.seek(SeekFrom::Current(size as i64 - header_size))?;
 
+            let track_desc = desc.moov_tracks.get_mut(tl_track).ok_or_else(|| invalid_data(format!("{} outside of a track", typ_to_str(typ))))?;
             let out_pos = output_file.stream_position()?;
             new_size = 12;
             output_file.write_all(&0u32.to_be_bytes())?;
             let new_typ = if typ == fourcc("stco") { fourcc("co64") } else { typ };
             output_file.write_all(&new_typ.to_be_bytes())?;
-            output_file.write_all(&0u32.to_be_bytes())?; // flags
-
-            let track_desc = desc.moov_tracks.get_mut(tl_track).unwrap();
+            // Version 1 of ctts has signed offsets
+            let version = if typ == fourcc("ctts") && track_desc.ctts.iter().any(|x| x.1 < 0) { 1u32 << 24 } else { 0 };
+            output_file.write_all(&version.to_be_bytes())?; // version and flags
             if typ == fourcc("stts") {
                 let mut new_stts: Vec<(u32, u32)> = Vec::with_capacity(track_desc.stts.len());
                 let mut prev_delta = None;
                 for x in &track_desc.stts {
                     if let Some(prev_delta) = prev_delta {
-                        if prev_delta == x.1 { new_stts.last_mut().unwrap().0 += x.0; continue; }
+                        if prev_delta == x.1 {
+                            if let Some(last) = new_stts.last_mut().filter(|last| last.0.checked_add(x.0).is_some()) { last.0 += x.0; continue; }
+                        }
                     }
                     prev_delta = Some(x.1);
                     new_stts.push(*x);
@@ -147,6 +252,15 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
                     new_size += 8;
                 }
             }
+            if typ == fourcc("ctts") {
+                output_file.write_u32::<BigEndian>(track_desc.ctts.len() as u32)?;
+                new_size += 4;
+                for x in &track_desc.ctts {
+                    output_file.write_u32::<BigEndian>(x.0)?;
+                    output_file.write_i32::<BigEndian>(x.1)?;
+                    new_size += 8;
+                }
+            }
             if typ == fourcc("sdtp") {
                 for x in &track_desc.sdtp { output_file.write_u8(*x)?; new_size += 1; }
             }
@@ -163,13 +277,13 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
             patch_bytes(output_file, out_pos, &(new_size as u32).to_be_bytes())?;
         } else {
             log::debug!("Writing original {}, offset: {}, size: {size}", typ_to_str(typ), offs);
-            let d = get_first(files);
+            let d = get_first(files)?;
 
             // Copy without changes
             d.seek(SeekFrom::Current(-header_size))?;
             std::io::copy(&mut d.take(size), output_file)?;
         }
-        total_new_size += new_size;
+        total_new_size = total_new_size.saturating_add(new_size);
         if total_read_size >= max_read {
             break;
         }
@@ -177,6 +291,50 @@ pub fn rewrite_from_desc<R: Read + Seek, W: Write + Seek>(files: &mut [(R, usize
     Ok(total_new_size)
 }
 
+/// Offset and size of the first top-level `typ` box between `offset` and `end`
+fn find_box<R: Read + Seek>(reader: &mut R, mut offset: u64, end: u64, typ: u32) -> Result<Option<(u64, u64)>> {
+    while offset < end {
+        reader.seek(SeekFrom::Start(offset))?;
+        let Ok((t, offs, size, _)) = read_box(reader) else { break; };
+        if t == typ { return Ok(Some((offs, size))); }
+        offset = offs.saturating_add(size);
+    }
+    Ok(None)
+}
+
+/// Writes the final chunk offsets, once the position of the merged `mdat` is known
+pub fn patch_chunk_offsets<W: Write + Seek>(output_file: &mut W, desc: &Desc) -> Result<()> {
+    for track in &desc.moov_tracks {
+        if track.excluded { continue; }
+        output_file.seek(SeekFrom::Start(track.co64_final_position))?;
+        for x in &track.stco {
+            output_file.write_u64::<BigEndian>(*x + desc.mdat_final_position)?;
+        }
+    }
+    Ok(())
+}
+
+/// Part of the output built in memory, at position `base` of the output
+struct OutputBuffer {
+    data: Cursor<Vec<u8>>,
+    base: u64,
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> { self.data.write(buf) }
+    fn flush(&mut self) -> Result<()> { Ok(()) }
+}
+
+impl Seek for OutputBuffer {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(x) => SeekFrom::Start(x.checked_sub(self.base).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeking before the buffer"))?),
+            x => x
+        };
+        Ok(self.data.seek(pos)? + self.base)
+    }
+}
+
 pub fn patch_bytes<W: Write + Seek>(writer: &mut W, position: u64, bytes: &[u8]) -> Result<()> {
     let new_pos = writer.stream_position()?;
     writer.seek(SeekFrom::Start(position))?;
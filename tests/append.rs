@@ -0,0 +1,54 @@
+mod common;
+
+use std::cell::RefCell;
+use common::*;
+use mp4_merge::{ append_file_streams, append_files, is_cancelled, join_files, verify_merge, CancellationToken, MergeOptions, VerifyMode };
+
+fn append_and_verify(name: &str, moov_first: bool) {
+    let dir = test_dir(name);
+    let chapters = chapters().into_iter().map(|x| Chapter { moov_first, ..x }).collect::<Vec<_>>();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("out.mp4");
+    join_files(&inputs[..1], &output, |_| { }).unwrap();
+
+    let progress = RefCell::new(Vec::new());
+    let report = append_files(&output, &inputs[1..], |x| progress.borrow_mut().push(x)).unwrap();
+    assert_eq!(report.output_size, std::fs::metadata(&output).unwrap().len());
+    assert_eq!(report.inputs.len(), 3);
+    let progress = progress.into_inner();
+    assert!(progress.windows(2).all(|x| x[0] <= x[1]), "{progress:?}");
+    assert_eq!(progress.last(), Some(&1.0));
+
+    let verify = verify_merge(&output, &inputs, VerifyMode::Full, |_| { }).unwrap();
+    assert!(verify.is_ok(), "{:?}", verify.mismatches);
+    assert_eq!(verify.samples_checked, chapters.iter().map(|x| (x.video_samples() + x.audio_samples()) as u64).sum::<u64>());
+}
+
+#[test]
+fn append_moov_after_mdat() {
+    append_and_verify("append_moov_last", false);
+}
+
+#[test]
+fn append_moov_before_mdat() {
+    append_and_verify("append_moov_first", true);
+}
+
+#[test]
+fn cancelled_append_keeps_the_existing_data() {
+    let dir = test_dir("append_cancel");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    join_files(&inputs[..1], &output, |_| { }).unwrap();
+    let original = std::fs::read(&output).unwrap();
+
+    let mut out = std::fs::OpenOptions::new().read(true).write(true).open(&output).unwrap();
+    let mut files = inputs[1..].iter().map(|x| (std::fs::File::open(x).unwrap(), std::fs::metadata(x).unwrap().len() as usize)).collect::<Vec<_>>();
+    let token = CancellationToken::new();
+    let options = MergeOptions { cancel: Some(token.clone()), ..Default::default() };
+    // Cancelled once the copy has started
+    let err = append_file_streams(&mut (&mut out, original.len()), &mut files, &options, |x| if x > 0.1 { token.cancel() }).unwrap_err();
+    assert!(is_cancelled(&err), "{err}");
+    drop(out);
+    assert_eq!(std::fs::read(&output).unwrap()[..original.len()], original[..]);
+}
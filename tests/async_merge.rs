@@ -0,0 +1,66 @@
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::io::{ ErrorKind, SeekFrom };
+use std::pin::Pin;
+use std::task::{ Context, Poll, Waker };
+use common::*;
+use mp4_merge::{ join_file_streams_async, join_files_with_options, MergeOptions };
+use tokio::io::{ AsyncRead, AsyncSeek, ReadBuf };
+
+/// Polls a future which never waits, as all the streams here are in memory
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    let mut future = std::pin::pin!(future);
+    loop {
+        if let Poll::Ready(x) = future.as_mut().poll(&mut Context::from_waker(Waker::noop())) { return x; }
+    }
+}
+
+#[test]
+fn async_merge_matches_the_sync_merge() {
+    let dir = test_dir("async_merge");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("sync.mp4");
+    join_files_with_options(&inputs, &output, &MergeOptions::default(), |_| { }).unwrap();
+
+    let mut streams = inputs.iter().map(|x| { let data = std::fs::read(x).unwrap(); let len = data.len(); (std::io::Cursor::new(data), len) }).collect::<Vec<_>>();
+    let mut out = Vec::new();
+    let report = block_on(join_file_streams_async(&mut streams, &mut out, &MergeOptions::default(), |_| { })).unwrap();
+    assert_eq!(report.output_size, out.len() as u64);
+    assert_eq!(out, std::fs::read(&output).unwrap());
+}
+
+/// A `moov` header with the given size, followed by zeros
+struct LargeBox { size: u64, pos: u64 }
+
+impl AsyncRead for LargeBox {
+    fn poll_read(mut self: Pin<&mut Self>, _: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let header = [&(self.size as u32).to_be_bytes()[..], b"moov"].concat();
+        let n = (buf.remaining() as u64).min(self.size - self.pos) as usize;
+        let data = (0..n).map(|i| header.get(self.pos as usize + i).copied().unwrap_or(0)).collect::<Vec<_>>();
+        buf.put_slice(&data);
+        self.pos += n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for LargeBox {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let SeekFrom::Start(x) = position else { unimplemented!() };
+        self.pos = x;
+        Ok(())
+    }
+    fn poll_complete(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[test]
+fn async_merge_rejects_boxes_too_large_to_load() {
+    let size = 512 * 1024 * 1024;
+    let mut streams = [(LargeBox { size, pos: 0 }, size as usize)];
+    let err = block_on(join_file_streams_async(&mut streams, Vec::new(), &MergeOptions::default(), |_| { })).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("too large"), "{err}");
+}
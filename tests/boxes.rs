@@ -0,0 +1,82 @@
+mod common;
+
+use std::io::{ Cursor, Seek, SeekFrom };
+use common::*;
+use mp4_merge::{ read_box, walk_boxes, join_files, verify_merge, VerifyMode };
+
+// Chapter with moov first and a size of 0 in the header of the trailing mdat, as written by recorders which don't patch it
+fn open_ended_chapter(seed: u32) -> (Vec<u8>, u64) {
+    let mut data = Chapter { seed, moov_first: true, ..Default::default() }.build();
+    let mdat = walk_boxes(&mut Cursor::new(&data)).unwrap().map(Result::unwrap).find(|x| x.path == "mdat").unwrap().header;
+    data[mdat.offset as usize..mdat.offset as usize + 4].fill(0);
+    (data, mdat.offset)
+}
+
+#[test]
+fn size_zero_box_extends_to_the_end() {
+    let (data, offset) = open_ended_chapter(1);
+    let mut stream = Cursor::new(&data);
+    stream.seek(SeekFrom::Start(offset)).unwrap();
+    let (typ, offs, size, header_size) = read_box(&mut stream).unwrap();
+    assert_eq!((&typ.to_be_bytes(), offs, size, header_size), (b"mdat", offset, data.len() as u64 - offset, 8));
+    assert_eq!(stream.position(), offset + 8);
+
+    let top_level = walk_boxes(&mut Cursor::new(&data)).unwrap().map(Result::unwrap).filter(|x| x.depth == 0).collect::<Vec<_>>();
+    assert_eq!(top_level.iter().map(|x| x.path.as_str()).collect::<Vec<_>>(), ["ftyp", "moov", "mdat"]);
+    assert_eq!(top_level[2].header.end(), data.len() as u64);
+}
+
+#[test]
+fn merge_trailing_size_zero_mdat() {
+    let dir = test_dir("size_zero_mdat");
+    let inputs = (0..2).map(|i| {
+        let path = dir.join(format!("c{i}.mp4"));
+        std::fs::write(&path, open_ended_chapter(i + 1).0).unwrap();
+        path
+    }).collect::<Vec<_>>();
+    let output = dir.join("out.mp4");
+    join_files(&inputs, &output, |_| { }).unwrap();
+
+    let report = verify_merge(&output, &inputs, VerifyMode::Full, |_| { }).unwrap();
+    assert!(report.is_ok(), "{:?}", report.mismatches);
+    let chapter = Chapter::default();
+    assert_eq!(report.samples_checked, 2 * (chapter.video_samples() + chapter.audio_samples()) as u64);
+
+    // The merged mdat has its real size
+    let data = std::fs::read(&output).unwrap();
+    let mdat = walk_boxes(&mut Cursor::new(&data)).unwrap().map(Result::unwrap).find(|x| x.path == "mdat").unwrap().header;
+    assert_ne!(&data[mdat.offset as usize..mdat.offset as usize + 4], [0; 4]);
+}
+
+#[test]
+fn box_tree_descends_into_containers_and_sample_entries() {
+    let data = Chapter::default().build();
+    let entries = walk_boxes(&mut Cursor::new(&data)).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+    let paths = entries.iter().map(|x| x.path.as_str()).collect::<Vec<_>>();
+    for path in ["ftyp", "mdat", "moov/mvhd", "moov/trak/edts/elst", "moov/trak/mdia/minf/dinf/dref/url ",
+                 "moov/trak/mdia/minf/stbl/stsd/avc1/avcC", "moov/trak/mdia/minf/stbl/stsd/mp4a/esds", "moov/trak/mdia/minf/stbl/stco"] {
+        assert!(paths.contains(&path), "{path} in {paths:?}");
+    }
+    // Each box lies in its parent, the last box before it with a lower depth
+    for (i, x) in entries.iter().enumerate() {
+        assert_eq!(x.path.split('/').count(), x.depth + 1);
+        if let Some(parent) = entries[..i].iter().rev().find(|p| p.depth < x.depth) {
+            assert_eq!(parent.depth + 1, x.depth);
+            assert!(x.header.offset >= parent.header.payload_offset() && x.header.end() <= parent.header.end(), "{x:?} in {parent:?}");
+        }
+    }
+}
+
+#[test]
+fn box_tree_stops_at_a_box_exceeding_its_parent() {
+    let mut data = Chapter::default().build();
+    let entries = walk_boxes(&mut Cursor::new(&data)).unwrap().map(Result::unwrap).collect::<Vec<_>>();
+    let stts = entries.iter().position(|x| x.path.ends_with("/stts")).unwrap();
+    let offset = entries[stts].header.offset as usize;
+    data[offset..offset + 4].copy_from_slice(&0x10000u32.to_be_bytes());
+
+    let walked = walk_boxes(&mut Cursor::new(&data)).unwrap().collect::<Vec<_>>();
+    assert_eq!(walked.len(), stts + 1);
+    assert!(walked[..stts].iter().all(Result::is_ok));
+    assert_eq!(walked[stts].as_ref().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+}
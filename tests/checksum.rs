@@ -0,0 +1,38 @@
+#![cfg(any(feature = "sha256", feature = "xxhash"))]
+
+mod common;
+
+use std::io::Cursor;
+use common::*;
+use mp4_merge::{ walk_boxes, ChecksumAlgorithm, Merger };
+
+fn mdat_payload(data: &[u8]) -> &[u8] {
+    let mdat = walk_boxes(&mut Cursor::new(data)).unwrap().map(Result::unwrap).find(|x| x.path == "mdat").unwrap().header;
+    &data[mdat.payload_offset() as usize..mdat.end() as usize]
+}
+
+fn check(algorithm: ChecksumAlgorithm, hash: fn(&[u8]) -> Vec<u8>) {
+    let dir = test_dir(&format!("checksum_{}", algorithm.name()));
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    let report = Merger::new().inputs(&inputs).output(&output).checksum(algorithm).run().unwrap();
+
+    // Each input's checksum is the hash of its mdat payload, the total one the hash of the merged mdat payload
+    for (x, input) in report.inputs.iter().zip(&inputs) {
+        assert_eq!(x.checksum.as_deref(), Some(&hash(mdat_payload(&std::fs::read(input).unwrap()))[..]));
+    }
+    assert_eq!(report.checksum, Some(hash(mdat_payload(&std::fs::read(&output).unwrap()))));
+}
+
+#[cfg(feature = "sha256")]
+#[test]
+fn sha256_of_the_media_data() {
+    use sha2::Digest;
+    check(ChecksumAlgorithm::Sha256, |x| sha2::Sha256::digest(x).to_vec());
+}
+
+#[cfg(feature = "xxhash")]
+#[test]
+fn xxh3_of_the_media_data() {
+    check(ChecksumAlgorithm::Xxh3, |x| xxhash_rust::xxh3::xxh3_64(x).to_be_bytes().to_vec());
+}
@@ -0,0 +1,191 @@
+// SPDX-License-Identifier: MIT OR Apache-2.0
+// Copyright © 2022 Adrian <adrian.eddy at gmail>
+
+// Synthetic chapters laid out like camera recordings: H.264 video with a keyframe every `gop` frames,
+// AAC-like audio, both interleaved in chunks inside one mdat.
+
+#![allow(dead_code)]
+
+use std::path::{ Path, PathBuf };
+
+pub const VIDEO_TIMESCALE: u32 = 30000;
+pub const FRAME_DURATION: u32 = 1000;
+pub const AUDIO_TIMESCALE: u32 = 48000;
+pub const AUDIO_FRAME: u32 = 1024;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Chapter {
+    pub seed: u32,
+    pub frames: u32,
+    pub gop: u32,
+    pub audio: bool,
+    pub moov_first: bool,
+    /// Alternating composition offsets of 2 and 0 frames
+    pub ctts: bool,
+}
+
+impl Default for Chapter {
+    fn default() -> Self {
+        Self { seed: 1, frames: 90, gop: 15, audio: true, moov_first: false, ctts: false }
+    }
+}
+
+struct Rng(u32);
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1103515245).wrapping_add(12345);
+        self.0 >> 8
+    }
+    fn bytes(&mut self, min: u32, max: u32) -> Vec<u8> {
+        let len = min + self.next() % (max - min);
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+fn bx(typ: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut ret = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    ret.extend_from_slice(typ);
+    ret.extend_from_slice(payload);
+    ret
+}
+
+fn full(typ: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    bx(typ, &[&(((version as u32) << 24) | flags).to_be_bytes()[..], payload].concat())
+}
+
+fn be(values: &[u32]) -> Vec<u8> {
+    values.iter().flat_map(|x| x.to_be_bytes()).collect()
+}
+
+fn table(typ: &[u8; 4], entries: &[Vec<u32>]) -> Vec<u8> {
+    full(typ, 0, 0, &[be(&[entries.len() as u32]), entries.iter().flat_map(|x| be(x)).collect()].concat())
+}
+
+const MATRIX: [u32; 9] = [0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x40000000];
+
+struct Track {
+    handler: &'static [u8; 4],
+    timescale: u32,
+    delta: u32,
+    samples: Vec<Vec<u8>>,
+    sync: Option<Vec<u32>>,
+    ctts: Option<Vec<Vec<u32>>>,
+    chunks: Vec<(usize, usize)>, // first sample, count
+}
+
+impl Chapter {
+    pub fn video_samples(&self) -> u32 { self.frames }
+    pub fn audio_samples(&self) -> u32 { if self.audio { self.frames * AUDIO_TIMESCALE / (VIDEO_TIMESCALE / FRAME_DURATION) / AUDIO_FRAME } else { 0 } }
+
+    /// Sample data of the video and the audio track
+    pub fn samples(&self) -> (Vec<Vec<u8>>, Vec<Vec<u8>>) {
+        let mut rng = Rng(self.seed);
+        let video = (0..self.frames).map(|i| {
+            let nal = if i % self.gop == 0 { [vec![0x65, 0x88], rng.bytes(200, 400)].concat() } else { [vec![0x41, 0x9a], rng.bytes(50, 150)].concat() };
+            [be(&[nal.len() as u32]), nal].concat()
+        }).collect::<Vec<_>>();
+        let audio = (0..self.audio_samples()).map(|_| [vec![0x21, 0x10], rng.bytes(150, 250)].concat()).collect::<Vec<_>>();
+        (video, audio)
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let (video, audio) = self.samples();
+
+        let mut tracks = vec![Track {
+            handler: b"vide", timescale: VIDEO_TIMESCALE, delta: FRAME_DURATION, samples: video,
+            sync: Some((0..self.frames).step_by(self.gop as usize).map(|x| x + 1).collect()),
+            ctts: self.ctts.then(|| (0..self.frames).map(|i| vec![1, if i % 2 == 0 { FRAME_DURATION * 2 } else { 0 }]).collect()),
+            chunks: Vec::new(),
+        }];
+        if self.audio {
+            tracks.push(Track { handler: b"soun", timescale: AUDIO_TIMESCALE, delta: AUDIO_FRAME, samples: audio, sync: None, ctts: None, chunks: Vec::new() });
+        }
+        // Interleave chunks of 15 video frames and 23 audio frames
+        let mut order = Vec::new();
+        let mut next = vec![0; tracks.len()];
+        while tracks.iter().zip(&next).any(|(t, n)| *n < t.samples.len()) {
+            for (i, t) in tracks.iter_mut().enumerate() {
+                let count = (if i == 0 { 15 } else { 23 }).min(t.samples.len() - next[i]);
+                if count == 0 { continue; }
+                t.chunks.push((next[i], count));
+                order.push((i, t.chunks.len() - 1));
+                next[i] += count;
+            }
+        }
+        let mut mdat = Vec::new();
+        let mut offsets = tracks.iter().map(|t| vec![0u32; t.chunks.len()]).collect::<Vec<_>>();
+        for &(t, c) in &order {
+            offsets[t][c] = mdat.len() as u32;
+            let (first, count) = tracks[t].chunks[c];
+            for s in &tracks[t].samples[first..first + count] { mdat.extend_from_slice(s); }
+        }
+
+        let ftyp = bx(b"ftyp", b"avc1\0\0\0\0avc1isom");
+        let moov_for = |data_pos: u32| {
+            let duration_ms = (self.frames * 1000).div_ceil(VIDEO_TIMESCALE / FRAME_DURATION);
+            let mvhd = full(b"mvhd", 0, 0, &[be(&[0, 0, 1000, duration_ms, 0x10000]), vec![1, 0], vec![0; 10], be(&MATRIX), vec![0; 24], be(&[tracks.len() as u32 + 1])].concat());
+            let mut moov = mvhd;
+            for (i, t) in tracks.iter().enumerate() {
+                let duration = t.samples.len() as u32 * t.delta;
+                let duration_ms = (duration as u64 * 1000).div_ceil(t.timescale as u64) as u32;
+                let (width, height) = if i == 0 { (1920, 1080) } else { (0, 0) };
+                let tkhd = full(b"tkhd", 0, 3, &[be(&[0, 0, i as u32 + 1, 0, duration_ms, 0, 0, 0, if i == 1 { 0x1000000 } else { 0 }]), be(&MATRIX), be(&[width << 16, height << 16])].concat());
+                let edts = bx(b"edts", &full(b"elst", 0, 0, &be(&[1, duration_ms, 0, 0x10000])));
+                let mdhd = full(b"mdhd", 0, 0, &[be(&[0, 0, t.timescale, duration]), vec![0x55, 0xc4, 0, 0]].concat());
+                let hdlr = full(b"hdlr", 0, 0, &[vec![0; 4], t.handler.to_vec(), vec![0; 12], b"Handler\0".to_vec()].concat());
+                let entry = if i == 0 {
+                    let avcc = bx(b"avcC", &[vec![1, 0x64, 0, 0x28, 0xff, 0xe1, 0, 4, 0x67, 0x64, 0, 0x28, 1, 0, 4, 0x68, 0xee, 0x3c, 0x80]].concat());
+                    bx(b"avc1", &[vec![0; 6], vec![0, 1], vec![0; 16], be(&[(1920 << 16) | 1080, 0x480000, 0x480000, 0]), vec![0, 1], vec![0; 32], vec![0, 24, 0xff, 0xff], avcc].concat())
+                } else {
+                    let esds = full(b"esds", 0, 0, &[vec![3, 25, 0, 1, 0, 4, 17, 0x40, 0x15], vec![0; 11], vec![5, 2, 0x11, 0x90, 6, 1, 2]].concat());
+                    bx(b"mp4a", &[vec![0; 6], vec![0, 1], vec![0; 8], vec![0, 2, 0, 16, 0, 0, 0, 0], be(&[AUDIO_TIMESCALE << 16]), esds].concat())
+                };
+                let mut stbl = full(b"stsd", 0, 0, &[be(&[1]), entry].concat());
+                stbl.extend(table(b"stts", &[vec![t.samples.len() as u32, t.delta]]));
+                if let Some(sync) = &t.sync { stbl.extend(table(b"stss", &sync.iter().map(|x| vec![*x]).collect::<Vec<_>>())); }
+                if let Some(ctts) = &t.ctts { stbl.extend(table(b"ctts", ctts)); }
+                stbl.extend(full(b"stsz", 0, 0, &[be(&[0, t.samples.len() as u32]), be(&t.samples.iter().map(|x| x.len() as u32).collect::<Vec<_>>())].concat()));
+                let mut stsc: Vec<Vec<u32>> = Vec::new();
+                for (c, (_, count)) in t.chunks.iter().enumerate() {
+                    if stsc.last().is_none_or(|x| x[1] != *count as u32) { stsc.push(vec![c as u32 + 1, *count as u32, 1]); }
+                }
+                stbl.extend(table(b"stsc", &stsc));
+                stbl.extend(table(b"stco", &offsets[i].iter().map(|x| vec![data_pos + x]).collect::<Vec<_>>()));
+                let mhd = if i == 0 { full(b"vmhd", 0, 1, &[0; 8]) } else { full(b"smhd", 0, 0, &[0; 4]) };
+                let dinf = bx(b"dinf", &full(b"dref", 0, 0, &[be(&[1]), full(b"url ", 0, 1, &[])].concat()));
+                let minf = bx(b"minf", &[mhd, dinf, bx(b"stbl", &stbl)].concat());
+                moov.extend(bx(b"trak", &[tkhd, edts, bx(b"mdia", &[mdhd, hdlr, minf].concat())].concat()));
+            }
+            bx(b"moov", &moov)
+        };
+        if self.moov_first {
+            let moov_size = moov_for(0).len();
+            [ftyp.clone(), moov_for((ftyp.len() + moov_size + 8) as u32), bx(b"mdat", &mdat)].concat()
+        } else {
+            let moov = moov_for(ftyp.len() as u32 + 8);
+            [ftyp, bx(b"mdat", &mdat), moov].concat()
+        }
+    }
+}
+
+/// Empty directory for the files of one test
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("mp4_merge_tests_{}", std::process::id())).join(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes the chapters as `c0.mp4`, `c1.mp4`, ... and returns their paths
+pub fn write_chapters(dir: &Path, chapters: &[Chapter]) -> Vec<PathBuf> {
+    chapters.iter().enumerate().map(|(i, c)| {
+        let path = dir.join(format!("c{i}.mp4"));
+        std::fs::write(&path, c.build()).unwrap();
+        path
+    }).collect()
+}
+
+/// Three chapters of different lengths
+pub fn chapters() -> Vec<Chapter> {
+    (0..3).map(|i| Chapter { seed: i + 1, frames: 90 + i * 30, ..Default::default() }).collect()
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use std::io::Cursor;
+use common::*;
+use mp4_merge::{ is_cancelled, CancellationToken, Merger, ProgressEvent };
+
+/// Merge of the chapters read as streams, which copies the media data through the generic path
+fn merged_from_streams(inputs: &[std::path::PathBuf]) -> Vec<u8> {
+    let mut merger = Merger::new();
+    for x in inputs {
+        let data = std::fs::read(x).unwrap();
+        let size = data.len();
+        merger = merger.input_stream(Cursor::new(data), size);
+    }
+    let mut output = Cursor::new(Vec::new());
+    merger.output_stream(&mut output).run().unwrap();
+    output.into_inner()
+}
+
+#[test]
+fn kernel_copy_matches_the_stream_copy() {
+    let dir = test_dir("copy_kernel");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    Merger::new().inputs(&inputs).output(&output).run().unwrap();
+    assert!(std::fs::read(&output).unwrap() == merged_from_streams(&inputs));
+}
+
+#[test]
+fn kernel_copy_stops_when_cancelled() {
+    let dir = test_dir("copy_kernel_cancel");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+    let err = Merger::new().inputs(&inputs).output(&output).cancel(token)
+        .progress(move |e| if matches!(e, ProgressEvent::Copying { .. }) { cancel.cancel(); })
+        .run().unwrap_err();
+    assert!(is_cancelled(&err), "{err}");
+    assert!(!output.exists());
+}
+
+#[test]
+fn pipelined_copy_matches_the_stream_copy() {
+    let dir = test_dir("copy_pipelined");
+    let inputs = write_chapters(&dir, &chapters());
+    // Paths into a stream are copied through the reader thread. Small buffers, so most copies end with a partial one
+    for buffer_size in [1000, 0] {
+        let mut output = Cursor::new(Vec::new());
+        Merger::new().inputs(&inputs).output_stream(&mut output).buffer_size(buffer_size).run().unwrap();
+        assert!(output.into_inner() == merged_from_streams(&inputs), "buffer size {buffer_size}");
+    }
+}
+
+#[test]
+fn pipelined_copy_fails_on_truncated_input() {
+    let dir = test_dir("copy_pipelined_truncated");
+    let inputs = write_chapters(&dir, &chapters());
+    let last = inputs[2].clone();
+    let mut output = Cursor::new(Vec::new());
+    // Cut the media data of the last input after the merge was planned
+    let err = Merger::new().inputs(&inputs).output_stream(&mut output).buffer_size(1000)
+        .progress(move |e| if matches!(e, ProgressEvent::Copying { file: 0, .. }) { std::fs::File::options().write(true).open(&last).unwrap().set_len(1000).unwrap(); })
+        .run().unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof, "{err}");
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use std::cell::RefCell;
+use common::*;
+use mp4_merge::{ extract_track, ExtractFormat };
+
+#[test]
+fn raw_extract_concatenates_the_samples() {
+    let dir = test_dir("extract_raw");
+    let chapters = chapters();
+    let inputs = write_chapters(&dir, &chapters);
+    for track in 0..2 {
+        let output = dir.join(format!("track{track}.bin"));
+        let progress = RefCell::new(Vec::new());
+        extract_track(&inputs, track, &output, ExtractFormat::Raw, |x| progress.borrow_mut().push(x)).unwrap();
+
+        let expected = chapters.iter().flat_map(|x| { let (video, audio) = x.samples(); if track == 0 { video } else { audio } }).flatten().collect::<Vec<u8>>();
+        assert_eq!(std::fs::read(&output).unwrap(), expected);
+        let progress = progress.into_inner();
+        assert!(progress.windows(2).all(|x| x[0] <= x[1]), "{progress:?}");
+        assert_eq!(progress.last(), Some(&1.0));
+    }
+}
+
+#[test]
+fn mp4_extract_keeps_only_the_track() {
+    let dir = test_dir("extract_mp4");
+    let chapters = chapters();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("audio.mp4");
+    extract_track(&inputs, 1, &output, ExtractFormat::Mp4, |_| { }).unwrap();
+
+    // The only track of the extracted file is the audio
+    let raw = dir.join("audio.bin");
+    extract_track(std::slice::from_ref(&output), 0, &raw, ExtractFormat::Raw, |_| { }).unwrap();
+    assert_eq!(std::fs::read(&raw).unwrap(), chapters.iter().flat_map(|x| x.samples().1).flatten().collect::<Vec<u8>>());
+    assert!(extract_track(&[output], 1, &raw, ExtractFormat::Raw, |_| { }).is_err());
+}
@@ -0,0 +1,56 @@
+mod common;
+
+use std::cell::RefCell;
+use std::io::{ Cursor, Read, Seek, SeekFrom };
+use std::rc::Rc;
+use common::*;
+use mp4_merge::{ join_files, walk_boxes, Merger };
+
+/// Records the `(position, length)` of the reads of a stream
+struct Recorded {
+    inner: Cursor<Vec<u8>>,
+    reads: Rc<RefCell<Vec<(u64, usize)>>>,
+}
+
+impl Read for Recorded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let pos = self.inner.position();
+        let read = self.inner.read(buf)?;
+        self.reads.borrow_mut().push((pos, read));
+        Ok(read)
+    }
+}
+impl Seek for Recorded {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> { self.inner.seek(pos) }
+}
+
+#[test]
+fn moov_is_read_at_once() {
+    // Long enough for sample tables larger than the read buffers
+    let chapters = (0..2).map(|i| Chapter { seed: i + 1, frames: 3000, ..Default::default() }).collect::<Vec<_>>();
+    let mut merger = Merger::new();
+    let mut recorded = Vec::new();
+    for c in &chapters {
+        let data = c.build();
+        let moov = walk_boxes(&mut Cursor::new(&data)).unwrap().map(Result::unwrap).find(|x| x.path == "moov").unwrap().header;
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        recorded.push((moov, reads.clone()));
+        let size = data.len();
+        merger = merger.input_stream(Recorded { inner: Cursor::new(data), reads }, size);
+    }
+    let mut output = Cursor::new(Vec::new());
+    merger.output_stream(&mut output).run().unwrap();
+
+    for (moov, reads) in recorded {
+        // Besides its header and the end of the file, checked for a vendor trailer, the moov is only read as a whole
+        let reads = reads.borrow();
+        assert!(reads.contains(&(moov.offset, moov.size as usize)), "{reads:?}");
+        assert!(!reads.iter().any(|x| x.0 >= moov.offset + 16 && x.0 + (x.1 as u64) < moov.end()), "{reads:?}");
+    }
+
+    // Same as merging the files
+    let dir = test_dir("moov_in_memory");
+    let expected = dir.join("expected.mp4");
+    join_files(&write_chapters(&dir, &chapters), &expected, |_| { }).unwrap();
+    assert!(output.into_inner() == std::fs::read(&expected).unwrap());
+}
@@ -0,0 +1,79 @@
+mod common;
+
+use std::cell::Cell;
+use std::io::{ Cursor, Read, Seek, SeekFrom };
+use std::path::PathBuf;
+use std::rc::Rc;
+use common::*;
+use mp4_merge::{ join_files, InputProvider, Merger };
+
+/// Chapters in memory, counting how many of them are open at once
+struct Chapters {
+    data: Vec<Vec<u8>>,
+    open: Rc<Cell<usize>>,
+    peak: Rc<Cell<usize>>,
+}
+
+struct Opened {
+    data: Cursor<Vec<u8>>,
+    open: Rc<Cell<usize>>,
+}
+
+impl Read for Opened {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> { self.data.read(buf) }
+}
+impl Seek for Opened {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> { self.data.seek(pos) }
+}
+impl Drop for Opened {
+    fn drop(&mut self) { self.open.set(self.open.get() - 1); }
+}
+
+impl InputProvider for Chapters {
+    type Input = Opened;
+    fn count(&self) -> usize { self.data.len() }
+    fn size(&mut self, index: usize) -> std::io::Result<u64> { Ok(self.data[index].len() as u64) }
+    fn open(&mut self, index: usize) -> std::io::Result<Opened> {
+        self.open.set(self.open.get() + 1);
+        self.peak.set(self.peak.get().max(self.open.get()));
+        Ok(Opened { data: Cursor::new(self.data[index].clone()), open: self.open.clone() })
+    }
+}
+
+fn five_chapters() -> Vec<Chapter> {
+    (0..5).map(|i| Chapter { seed: i + 1, frames: 60 + i * 15, ..Default::default() }).collect()
+}
+
+fn provider(paths: &[PathBuf]) -> (Chapters, Rc<Cell<usize>>) {
+    let peak = Rc::new(Cell::new(0));
+    (Chapters { data: paths.iter().map(|x| std::fs::read(x).unwrap()).collect(), open: Rc::new(Cell::new(0)), peak: peak.clone() }, peak)
+}
+
+#[test]
+fn provider_inputs_stay_within_the_limit() {
+    let dir = test_dir("pool_provider");
+    let inputs = write_chapters(&dir, &five_chapters());
+    let expected = dir.join("expected.mp4");
+    join_files(&inputs, &expected, |_| { }).unwrap();
+
+    let (chapters, peak) = provider(&inputs);
+    let output = dir.join("out.mp4");
+    Merger::new().input_provider(chapters).max_open_files(2).output(&output).run().unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&expected).unwrap());
+    assert_eq!(peak.get(), 2);
+}
+
+#[test]
+fn copies_from_paths_count_towards_the_limit() {
+    let dir = test_dir("pool_paths");
+    let inputs = write_chapters(&dir, &five_chapters());
+    let expected = dir.join("expected.mp4");
+    join_files(&inputs, &expected, |_| { }).unwrap();
+
+    // The first input is a path, which is copied from a file of its own
+    let (chapters, peak) = provider(&inputs[1..]);
+    let output = dir.join("out.mp4");
+    Merger::new().input(&inputs[0]).input_provider(chapters).max_open_files(2).output(&output).run().unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), std::fs::read(&expected).unwrap());
+    assert_eq!(peak.get(), 1);
+}
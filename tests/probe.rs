@@ -0,0 +1,42 @@
+mod common;
+
+use common::*;
+use mp4_merge::{ join_files, probe };
+
+#[test]
+fn probe_describes_a_chapter() {
+    let dir = test_dir("probe_chapter");
+    let chapter = Chapter::default();
+    let inputs = write_chapters(&dir, &[chapter]);
+    let info = probe(&inputs).unwrap();
+    assert_eq!((info.files, info.timescale, info.duration), (1, 1000, 3000));
+    assert_eq!(info.tracks.len(), 2);
+
+    let (video, audio) = chapter.samples();
+    let (v, a) = (&info.tracks[0], &info.tracks[1]);
+    assert_eq!((v.handler.as_str(), v.codec.as_str(), v.width, v.height), ("vide", "avc1", 1920, 1080));
+    assert_eq!((v.timescale, v.duration, v.sample_count, v.keyframe_count), (VIDEO_TIMESCALE, (chapter.frames * FRAME_DURATION) as u64, chapter.frames, chapter.frames / chapter.gop));
+    assert_eq!(v.media_data_size, video.iter().map(|x| x.len() as u64).sum::<u64>());
+    assert_eq!((a.handler.as_str(), a.codec.as_str(), a.sample_rate, a.timescale), ("soun", "mp4a", AUDIO_TIMESCALE, AUDIO_TIMESCALE));
+    // Every sample of a track without stss is a sync sample
+    assert_eq!((a.sample_count, a.keyframe_count), (chapter.audio_samples(), chapter.audio_samples()));
+    assert_eq!(a.media_data_size, audio.iter().map(|x| x.len() as u64).sum::<u64>());
+    assert_eq!(info.media_data_size, v.media_data_size + a.media_data_size);
+    assert!(v.merged && a.merged);
+}
+
+#[test]
+fn probe_of_several_files_describes_the_merge() {
+    let dir = test_dir("probe_merge");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    join_files(&inputs, &output, |_| { }).unwrap();
+
+    let planned = probe(&inputs).unwrap();
+    let merged = probe(&[output]).unwrap();
+    assert_eq!(planned.files, 3);
+    assert_eq!((planned.timescale, planned.duration, planned.media_data_size), (merged.timescale, merged.duration, merged.media_data_size));
+    for (p, m) in planned.tracks.iter().zip(&merged.tracks) {
+        assert_eq!((p.duration, p.sample_count, p.keyframe_count, p.media_data_size), (m.duration, m.sample_count, m.keyframe_count, m.media_data_size));
+    }
+}
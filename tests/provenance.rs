@@ -0,0 +1,52 @@
+mod common;
+
+use common::*;
+use mp4_merge::{ append_files, join_files, join_files_with_options, read_provenance, MergeOptions };
+
+fn provenance_of(path: &std::path::Path) -> Option<mp4_merge::Provenance> {
+    read_provenance(&mut std::fs::File::open(path).unwrap()).unwrap()
+}
+
+#[test]
+fn provenance_describes_the_sources() {
+    let dir = test_dir("provenance");
+    let chapters = chapters();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("out.mp4");
+    let report = join_files_with_options(&inputs, &output, &MergeOptions { provenance: true, ..Default::default() }, |_| { }).unwrap();
+
+    let provenance = provenance_of(&output).unwrap();
+    assert_eq!(provenance.timescale, report.timescale);
+    assert_eq!(provenance.sources.iter().map(|x| x.name.as_str()).collect::<Vec<_>>(), ["c0.mp4", "c1.mp4", "c2.mp4"]);
+    let mut first_sample = 0;
+    for ((source, input), (chapter, x)) in provenance.sources.iter().zip(&inputs).zip(chapters.iter().zip(&report.inputs)) {
+        assert_eq!(source.size, std::fs::metadata(input).unwrap().len());
+        assert_eq!((source.start_time, source.duration, source.data_size), (x.start_time, x.duration, x.data_size));
+        assert_eq!(source.tracks[0], (first_sample, chapter.video_samples()));
+        first_sample += chapter.video_samples();
+    }
+}
+
+#[test]
+fn provenance_is_optional() {
+    let dir = test_dir("provenance_off");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    join_files(&inputs, &output, |_| { }).unwrap();
+    assert_eq!(provenance_of(&output), None);
+}
+
+#[test]
+fn provenance_is_extended_when_appending() {
+    let dir = test_dir("provenance_append");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    join_files_with_options(&inputs[..2], &output, &MergeOptions { provenance: true, ..Default::default() }, |_| { }).unwrap();
+    let before = provenance_of(&output).unwrap();
+    append_files(&output, &inputs[2..], |_| { }).unwrap();
+
+    let after = provenance_of(&output).unwrap();
+    assert_eq!(after.sources[..2], before.sources[..]);
+    assert_eq!(after.sources[2].name, "c2.mp4");
+    assert_eq!(after.sources[2].start_time, 7000);
+}
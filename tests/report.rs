@@ -0,0 +1,56 @@
+mod common;
+
+use std::io::Cursor;
+use common::*;
+use mp4_merge::{ join_files, join_files_with_options, walk_boxes, MergeOptions };
+
+fn mdat_payload(data: &[u8]) -> &[u8] {
+    let mdat = walk_boxes(&mut Cursor::new(data)).unwrap().map(Result::unwrap).find(|x| x.path == "mdat").unwrap().header;
+    &data[mdat.payload_offset() as usize..mdat.end() as usize]
+}
+
+#[test]
+fn report_input_boundaries() {
+    let dir = test_dir("report");
+    let chapters = chapters();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("out.mp4");
+    let report = join_files(&inputs, &output, |_| { }).unwrap();
+    let data = std::fs::read(&output).unwrap();
+
+    assert_eq!(report.output_size, data.len() as u64);
+    assert_eq!(report.timescale, 1000);
+    assert_eq!(report.duration, 12000);
+    assert!(report.skipped.is_empty());
+    assert_eq!(report.inputs.iter().map(|x| (x.index, x.start_time, x.duration)).collect::<Vec<_>>(), [(0, 0, 3000), (1, 3000, 4000), (2, 7000, 5000)]);
+    let mut first_sample = 0;
+    for ((x, chapter), input) in report.inputs.iter().zip(&chapters).zip(&inputs) {
+        // The media data of each input is copied as is
+        let media = &data[x.data_offset as usize..(x.data_offset + x.data_size) as usize];
+        assert_eq!(media, mdat_payload(&std::fs::read(input).unwrap()));
+        let video = &x.tracks[0];
+        assert_eq!((video.first_sample, video.sample_count, video.timescale), (first_sample, chapter.video_samples(), VIDEO_TIMESCALE));
+        assert_eq!(video.start_time, first_sample as u64 * FRAME_DURATION as u64);
+        assert_eq!(x.tracks[1].sample_count, chapter.audio_samples());
+        first_sample += chapter.video_samples();
+    }
+    assert_eq!(report.media_data_size, report.inputs.iter().map(|x| x.data_size).sum::<u64>());
+
+    let json = report.to_json();
+    assert!(json.contains("\"start_time\": 3,"), "{json}");
+    assert!(json.contains(&format!("\"output_size\": {},", data.len())), "{json}");
+}
+
+#[test]
+fn report_skipped_inputs() {
+    let dir = test_dir("report_skipped");
+    let mut inputs = write_chapters(&dir, &chapters()[..2]);
+    let broken = dir.join("broken.mp4");
+    std::fs::write(&broken, b"not an mp4 file").unwrap();
+    inputs.insert(1, broken);
+    let report = join_files_with_options(&inputs, &dir.join("out.mp4"), &MergeOptions { tolerant: true, ..Default::default() }, |_| { }).unwrap();
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].index, 1);
+    assert_eq!(report.inputs.iter().map(|x| x.index).collect::<Vec<_>>(), [0, 2]);
+    assert_eq!(report.inputs[1].start_time, 3000);
+}
@@ -0,0 +1,58 @@
+mod common;
+
+use std::fs::File;
+use std::io::{ Read, Write, Seek, SeekFrom, Result };
+use std::time::Duration;
+use common::*;
+use mp4_merge::{ join_files, Merger };
+
+// Output which counts the written bytes, and fails after `fail_at` of them. It stalls once halfway, long enough for the
+// journal to be updated.
+struct Output { file: File, written: u64, fail_at: u64, stalled: bool }
+
+impl Read for Output {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> { self.file.read(buf) }
+}
+impl Seek for Output {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> { self.file.seek(pos) }
+}
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.written >= self.fail_at {
+            return Err(std::io::Error::other("Disk disconnected"));
+        }
+        if !self.stalled && self.written >= self.fail_at / 2 {
+            self.stalled = true;
+            std::thread::sleep(Duration::from_millis(1100));
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> Result<()> { self.file.flush() }
+}
+
+#[test]
+fn resumed_merge_matches_uninterrupted() {
+    let dir = test_dir("resume");
+    let inputs = write_chapters(&dir, &chapters());
+    let reference = dir.join("reference.mp4");
+    join_files(&inputs, &reference, |_| { }).unwrap();
+    let reference = std::fs::read(&reference).unwrap();
+
+    let (output, journal) = (dir.join("out.mp4"), dir.join("out.mp4.journal"));
+    let open = |fail_at| Output { file: File::options().read(true).write(true).create(true).truncate(false).open(&output).unwrap(), written: 0, fail_at, stalled: false };
+    let fail_at = reference.len() as u64 * 2 / 3;
+    let err = Merger::new().inputs(&inputs).output_stream(open(fail_at)).journal(&journal).buffer_size(4096).run().unwrap_err();
+    assert_eq!(err.to_string(), "Disk disconnected");
+    assert!(journal.exists());
+
+    let mut resumed = open(u64::MAX);
+    resumed.stalled = true;
+    let report = Merger::new().inputs(&inputs).output_stream(&mut resumed).journal(&journal).buffer_size(4096).run().unwrap();
+    assert_eq!(report.output_size, reference.len() as u64);
+    assert_eq!(std::fs::read(&output).unwrap(), reference);
+    assert!(!journal.exists());
+    // The media data written before the failure wasn't copied again
+    assert!(resumed.written < reference.len() as u64 - fail_at / 3, "{} bytes written", resumed.written);
+}
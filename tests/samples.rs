@@ -0,0 +1,49 @@
+mod common;
+
+use common::*;
+use mp4_merge::{ join_files, sample_table };
+
+fn composition_offsets(files: &[std::path::PathBuf], track: usize) -> Vec<i64> {
+    sample_table(files).unwrap().track(track).map(|x| x.pts - x.dts as i64).collect()
+}
+
+#[test]
+fn merged_composition_offsets() {
+    let dir = test_dir("composition_offsets");
+    // The middle chapter has no ctts, its samples have no composition offset
+    let chapters = chapters().into_iter().enumerate().map(|(i, c)| Chapter { ctts: i != 1, ..c }).collect::<Vec<_>>();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("out.mp4");
+    join_files(&inputs, &output, |_| { }).unwrap();
+
+    let expected = inputs.iter().flat_map(|x| composition_offsets(std::slice::from_ref(x), 0)).collect::<Vec<_>>();
+    assert_eq!(expected.len(), chapters.iter().map(|x| x.video_samples() as usize).sum::<usize>());
+    assert!(expected.iter().any(|x| *x != 0));
+    assert_eq!(composition_offsets(&[output], 0), expected);
+    // The planned merge describes the same output
+    assert_eq!(composition_offsets(&inputs, 0), expected);
+}
+
+#[test]
+fn merged_sample_table() {
+    let dir = test_dir("sample_table");
+    let chapters = chapters();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("out.mp4");
+    join_files(&inputs, &output, |_| { }).unwrap();
+
+    let planned = sample_table(&inputs).unwrap();
+    let actual = sample_table(&[&output]).unwrap();
+    assert_eq!(planned.track_count(), 2);
+    for t in 0..planned.track_count() {
+        let planned = planned.track(t).collect::<Vec<_>>();
+        let actual = actual.track(t).collect::<Vec<_>>();
+        assert_eq!(planned.len(), actual.len());
+        for (p, a) in planned.iter().zip(&actual) {
+            assert_eq!((p.dts, p.pts, p.duration, p.size, p.sync, p.merged_offset), (a.dts, a.pts, a.duration, a.size, a.sync, a.merged_offset));
+        }
+        // Sources follow the order of the inputs
+        assert!(planned.windows(2).all(|x| x[0].source <= x[1].source));
+        assert_eq!(planned.last().unwrap().source, inputs.len() - 1);
+    }
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use std::io::ErrorKind;
+use common::*;
+use mp4_merge::{ join_files_with_options, split_file, verify_merge, sample_table, MergeOptions, SplitAt, VerifyMode };
+
+#[test]
+fn split_file_without_tracks() {
+    let dir = test_dir("split_no_tracks");
+    // mdat but no moov, e.g. a recording which was cut off
+    let path = dir.join("nomoov.mp4");
+    std::fs::write(&path, [&16u32.to_be_bytes()[..], b"ftypisom", &[0; 4], &16u32.to_be_bytes(), b"mdat", &[0; 8]].concat()).unwrap();
+    for at in [SplitAt::Times(vec![0.5]), SplitAt::MaxSize(1000)] {
+        let err = split_file(&path, &at, |_| { }).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{at:?}: {err}");
+    }
+    assert!(!dir.join("nomoov_part01.mp4").exists());
+}
+
+#[test]
+fn split_into_the_original_chapters() {
+    let dir = test_dir("split_provenance");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("merged.mp4");
+    join_files_with_options(&inputs, &output, &MergeOptions { provenance: true, ..Default::default() }, |_| { }).unwrap();
+
+    let parts = split_file(&output, &SplitAt::Provenance, |_| { }).unwrap();
+    assert_eq!(parts.len(), inputs.len());
+    for (part, input) in parts.iter().zip(&inputs) {
+        let report = verify_merge(part, std::slice::from_ref(input), VerifyMode::Full, |_| { }).unwrap();
+        assert!(report.is_ok(), "{part:?}: {:?}", report.mismatches);
+    }
+}
+
+#[test]
+fn split_at_keyframes() {
+    let dir = test_dir("split_times");
+    let inputs = write_chapters(&dir, &[Chapter { frames: 120, gop: 15, ..Default::default() }]);
+    // 1.2s is in the middle of a GOP, the cut moves back to the keyframe at 1.0s
+    let parts = split_file(&inputs[0], &SplitAt::Times(vec![1.2, 3.0]), |_| { }).unwrap();
+    assert_eq!(parts.len(), 3);
+    let frames = parts.iter().map(|x| {
+        let table = sample_table(std::slice::from_ref(x)).unwrap();
+        let video = table.track(0).collect::<Vec<_>>();
+        assert!(video[0].sync);
+        video.len()
+    }).collect::<Vec<_>>();
+    assert_eq!(frames, [30, 60, 30]);
+}
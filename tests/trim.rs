@@ -0,0 +1,96 @@
+mod common;
+
+use std::io::{ Cursor, ErrorKind };
+use common::*;
+use mp4_merge::{ join_files_with_options, sample_table, walk_boxes, probe, MergeOptions, TimeRange };
+
+// Payloads of all boxes at `path`, e.g. `moov/trak/tkhd`
+fn payloads(data: &[u8], path: &str) -> Vec<Vec<u8>> {
+    walk_boxes(&mut Cursor::new(data)).unwrap().map(Result::unwrap).filter(|x| x.path == path)
+        .map(|x| data[x.header.payload_offset() as usize..x.header.end() as usize].to_vec()).collect()
+}
+
+fn u32_at(data: &[u8], pos: usize) -> u32 { u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) }
+
+// Version 0 tkhd durations of all tracks
+fn tkhd_durations(data: &[u8]) -> Vec<u32> {
+    payloads(data, "moov/trak/tkhd").iter().map(|x| { assert_eq!(x[0], 0); u32_at(x, 20) }).collect()
+}
+
+// elst entries of all tracks, (segment duration, media time)
+fn edit_lists(data: &[u8]) -> Vec<Vec<(u64, i64)>> {
+    payloads(data, "moov/trak/edts/elst").iter().map(|x| {
+        (0..u32_at(x, 4) as usize).map(|i| if x[0] == 1 {
+            let entry = &x[8 + i * 20..];
+            (u64::from_be_bytes(entry[..8].try_into().unwrap()), i64::from_be_bytes(entry[8..16].try_into().unwrap()))
+        } else {
+            (u32_at(x, 8 + i * 12) as u64, u32_at(x, 12 + i * 12) as i32 as i64)
+        }).collect()
+    }).collect()
+}
+
+fn merge_range(name: &str, start: f64, end: f64, edit_list: bool) -> (Vec<Vec<mp4_merge::SampleInfo>>, Vec<u8>, std::path::PathBuf) {
+    let dir = test_dir(name);
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    let options = MergeOptions { keep: vec![TimeRange { input: None, start, end }], edit_list, ..Default::default() };
+    join_files_with_options(&inputs, &output, &options, |_| { }).unwrap();
+    let table = sample_table(&[&output]).unwrap();
+    let samples = (0..table.track_count()).map(|t| table.track(t).collect()).collect();
+    (samples, std::fs::read(&output).unwrap(), output)
+}
+
+fn check_sync_samples(video: &[mp4_merge::SampleInfo]) {
+    // Keyframes every 15 frames from the first one
+    assert!(video.iter().all(|x| x.sync == (x.index % 15 == 0)));
+}
+
+#[test]
+fn keep_from_keyframe() {
+    // 1.2s is in the middle of the GOP starting at 1.0s, 4.1s is in the second chapter
+    let (samples, data, output) = merge_range("keep", 1.2, 4.1, false);
+    let (video, audio) = (&samples[0], &samples[1]);
+    check_sync_samples(video);
+    // Frames 30..123, audio frames starting at 1.0s and ending before 4.1s
+    assert_eq!(video.len(), 93);
+    assert_eq!(audio.len(), (4.1f64 * 48000.0 / 1024.0).ceil() as usize - (48000.0f64 / 1024.0).ceil() as usize);
+    assert_eq!(video.iter().map(|x| x.duration as u64).sum::<u64>(), 93 * FRAME_DURATION as u64);
+
+    let info = probe(&[&output]).unwrap();
+    assert_eq!(info.tracks[0].duration, 93 * FRAME_DURATION as u64);
+    assert_eq!(info.tracks[1].duration, audio.len() as u64 * AUDIO_FRAME as u64);
+    let audio_duration = (audio.len() as u64 * AUDIO_FRAME as u64 * 1000).div_ceil(AUDIO_TIMESCALE as u64);
+    assert_eq!(tkhd_durations(&data), [3100, audio_duration as u32]);
+    assert_eq!(info.duration, audio_duration.max(3100));
+}
+
+#[test]
+fn keep_with_edit_list() {
+    let (samples, data, output) = merge_range("keep_edit_list", 1.2, 4.1, true);
+    let (video, audio) = (&samples[0], &samples[1]);
+    // The same samples as without the edit list
+    check_sync_samples(video);
+    assert_eq!(video.len(), 93);
+    assert!(audio.len() > 100);
+
+    // The 0.2s before the requested start are hidden, the presentation is 2.9s long
+    let edits = edit_lists(&data);
+    assert_eq!(edits[0], [(2900, 6 * FRAME_DURATION as i64)]);
+    assert_eq!(edits[1].len(), 1);
+    assert_eq!(edits[1][0].0, 2900);
+    // Audio starts at the first frame at or after 1.0s
+    let audio_start = 47 * AUDIO_FRAME;
+    assert_eq!(edits[1][0].1, (1.2 * AUDIO_TIMESCALE as f64).round() as i64 - audio_start as i64);
+    assert_eq!(tkhd_durations(&data), [2900, 2900]);
+    assert_eq!(probe(&[&output]).unwrap().duration, 2900);
+}
+
+#[test]
+fn keep_without_tracks() {
+    let dir = test_dir("keep_no_tracks");
+    let path = dir.join("nomoov.mp4");
+    std::fs::write(&path, [&16u32.to_be_bytes()[..], b"ftypisom", &[0; 4], &16u32.to_be_bytes(), b"mdat", &[0; 8]].concat()).unwrap();
+    let options = MergeOptions { keep: vec![TimeRange { input: None, start: 0.1, end: 0.5 }], ..Default::default() };
+    let err = join_files_with_options(&[path], &dir.join("out.mp4"), &options, |_| { }).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
@@ -0,0 +1,47 @@
+mod common;
+
+use common::*;
+use mp4_merge::{ join_files, verify_merge, VerifyMode };
+
+#[test]
+fn verify_merged_output() {
+    let dir = test_dir("verify");
+    let chapters = chapters();
+    let inputs = write_chapters(&dir, &chapters);
+    let output = dir.join("out.mp4");
+    let report = join_files(&inputs, &output, |_| { }).unwrap();
+
+    let total = chapters.iter().map(|x| (x.video_samples() + x.audio_samples()) as u64).sum::<u64>();
+    let full = verify_merge(&output, &inputs, VerifyMode::Full, |_| { }).unwrap();
+    assert!(full.is_ok(), "{:?}", full.mismatches);
+    assert_eq!(full.samples_checked, total);
+    let spot = verify_merge(&output, &inputs, VerifyMode::Spot(5), |_| { }).unwrap();
+    assert!(spot.is_ok());
+    assert!(spot.samples_checked > 0 && spot.samples_checked < total);
+    assert_eq!(verify_merge(&output, &inputs, VerifyMode::Tables, |_| { }).unwrap().samples_checked, 0);
+
+    // A changed byte of the media data of the second input
+    let mut data = std::fs::read(&output).unwrap();
+    data[(report.inputs[1].data_offset + 10) as usize] ^= 0xff;
+    std::fs::write(&output, data).unwrap();
+    let full = verify_merge(&output, &inputs, VerifyMode::Full, |_| { }).unwrap();
+    assert_eq!(full.mismatches.len(), 1, "{:?}", full.mismatches);
+    assert!(full.mismatches[0].contains("differs from input 1"), "{:?}", full.mismatches);
+    assert!(verify_merge(&output, &inputs, VerifyMode::Tables, |_| { }).unwrap().is_ok());
+}
+
+#[test]
+fn verify_against_wrong_inputs() {
+    let dir = test_dir("verify_wrong_inputs");
+    let inputs = write_chapters(&dir, &chapters());
+    let output = dir.join("out.mp4");
+    join_files(&inputs, &output, |_| { }).unwrap();
+
+    // Missing the last input
+    let report = verify_merge(&output, &inputs[..2], VerifyMode::Tables, |_| { }).unwrap();
+    assert!(!report.is_ok());
+    assert!(report.mismatches[0].starts_with("Track 0: 360 samples, expected 210"), "{:?}", report.mismatches);
+    // Different order
+    let swapped = [inputs[1].clone(), inputs[0].clone(), inputs[2].clone()];
+    assert!(!verify_merge(&output, &swapped, VerifyMode::Full, |_| { }).unwrap().is_ok());
+}